@@ -0,0 +1,463 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, TimeZone};
+
+use error::CommandError;
+
+// NOTE: 28 years is a full cycle of the calendar so a schedule that
+// doesn't fire within it never will.
+const MAX_SEARCH_DAYS: u32 = 28 * 366;
+
+const MONTH_NAMES: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const DAY_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// The set of values a single cron field allows.
+#[derive(Debug, Clone)]
+struct Field {
+    values: Vec<u32>,
+    // True if the field was written as `*` or `*/n`. Needed for the day of
+    // month and day of week rule below.
+    star: bool,
+}
+
+impl Field {
+    fn parse(
+        spec: &str,
+        name: &str,
+        min: u32,
+        max: u32,
+        names: &[&str],
+    ) -> Result<Field, CommandError> {
+        let mut allowed = vec![false; (max + 1) as usize];
+        for part in spec.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => (range, Some(step)),
+                    _ => {
+                        return Err(CommandError::new(format!(
+                            "Invalid step {:?} in cron {} field",
+                            step, name
+                        )))
+                    }
+                },
+                None => (part, None),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (
+                    parse_value(start, name, min, max, names)?,
+                    parse_value(end, name, min, max, names)?,
+                )
+            } else {
+                let start = parse_value(range, name, min, max, names)?;
+                // NOTE: `5/15` means from 5 to the end in steps of 15.
+                match step {
+                    Some(_) => (start, max),
+                    None => (start, start),
+                }
+            };
+            if start > end {
+                return Err(CommandError::new(format!(
+                    "Invalid range {:?} in cron {} field",
+                    range, name
+                )));
+            }
+            let step = step.unwrap_or(1) as usize;
+            for v in (start..=end).step_by(step) {
+                allowed[v as usize] = true;
+            }
+        }
+        Ok(Field {
+            values: (min..=max).filter(|v| allowed[*v as usize]).collect(),
+            star: spec.starts_with('*'),
+        })
+    }
+
+    fn contains(&self, v: u32) -> bool {
+        self.values.contains(&v)
+    }
+}
+
+fn parse_value(
+    s: &str,
+    name: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+) -> Result<u32, CommandError> {
+    let lower = s.to_lowercase();
+    let offset = if names.len() == 12 { 1 } else { 0 };
+    if let Some(i) = names.iter().position(|n| *n == lower) {
+        return Ok(i as u32 + offset);
+    }
+    match s.parse::<u32>() {
+        Ok(v) if v >= min && v <= max => Ok(v),
+        _ => Err(CommandError::new(format!(
+            "Invalid value {:?} in cron {} field",
+            s, name
+        ))),
+    }
+}
+
+/// A cron schedule.
+///
+/// Accepts the standard five fields (minute, hour, day of month, month and
+/// day of week), an optional leading seconds field, and the `@yearly`,
+/// `@annually`, `@monthly`, `@weekly`, `@daily`, `@midnight` and `@hourly`
+/// aliases. Fields may use lists, ranges, steps and month or day names.
+/// Sunday is either 0 or 7. As in cron, when both the day of month and day
+/// of week are restricted a day matching either one fires.
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    seconds: Field,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl CronSchedule {
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        if !self.months.contains(date.month()) {
+            return false;
+        }
+        let day = self.days.contains(date.day());
+        let weekday = self
+            .weekdays
+            .contains(date.weekday().num_days_from_sunday());
+        if self.days.star || self.weekdays.star {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+
+    /// Returns the first time after `after` that the schedule fires in the
+    /// timezone of `after`.
+    ///
+    /// Times are matched against the local wall clock. A time skipped when
+    /// the clock jumps forward for daylight saving fires an hour later
+    /// instead. A time repeated when the clock goes back fires both times if
+    /// the schedule runs every hour, and only the first time otherwise.
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let tz = after.timezone();
+        let every_hour = self.hours.values.len() == 24;
+        let mut date = after.naive_local().date();
+        for _ in 0..MAX_SEARCH_DAYS {
+            if self.day_matches(date) {
+                // NOTE: The second occurrences of a repeated hour come after
+                // the first occurrences of every time in it, so we keep the
+                // earliest candidate until we are past the repeated hour.
+                let mut repeated: Option<DateTime<Tz>> = None;
+                for h in self.hours.values.iter() {
+                    for m in self.minutes.values.iter() {
+                        for s in self.seconds.values.iter() {
+                            let naive = date.and_hms_opt(*h, *m, *s)?;
+                            let time = match tz.from_local_datetime(&naive) {
+                                LocalResult::Single(t) => t,
+                                LocalResult::Ambiguous(a, b) => {
+                                    let (first, second) = if a < b { (a, b) } else { (b, a) };
+                                    let mut candidates = vec![first];
+                                    if every_hour {
+                                        candidates.push(second);
+                                    }
+                                    for t in candidates {
+                                        if t > *after && repeated.as_ref().is_none_or(|r| t < *r) {
+                                            repeated = Some(t);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                LocalResult::None => {
+                                    match tz
+                                        .from_local_datetime(&(naive + Duration::hours(1)))
+                                        .earliest()
+                                    {
+                                        Some(t) => t,
+                                        None => continue,
+                                    }
+                                }
+                            };
+                            if time > *after {
+                                return match repeated {
+                                    Some(r) if r < time => Some(r),
+                                    _ => Some(time),
+                                };
+                            }
+                        }
+                    }
+                }
+                if repeated.is_some() {
+                    return repeated;
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<CronSchedule, CommandError> {
+        let expanded = match s.trim().to_lowercase().as_str() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            alias if alias.starts_with('@') => {
+                return Err(CommandError::new(format!("Unknown cron alias {:?}", s)))
+            }
+            _ => s,
+        };
+        let mut fields: Vec<&str> = expanded.split_whitespace().collect();
+        match fields.len() {
+            5 => fields.insert(0, "0"),
+            6 => (),
+            _ => {
+                return Err(CommandError::new(format!(
+                    "Invalid cron expression {:?}. Expected 5 or 6 fields",
+                    s
+                )))
+            }
+        }
+        let mut weekdays = Field::parse(fields[5], "day of week", 0, 7, DAY_NAMES)?;
+        if weekdays.contains(7) {
+            weekdays.values.retain(|v| *v != 7);
+            if !weekdays.contains(0) {
+                weekdays.values.insert(0, 0);
+            }
+        }
+        let schedule = CronSchedule {
+            seconds: Field::parse(fields[0], "seconds", 0, 59, &[])?,
+            minutes: Field::parse(fields[1], "minute", 0, 59, &[])?,
+            hours: Field::parse(fields[2], "hour", 0, 23, &[])?,
+            days: Field::parse(fields[3], "day of month", 1, 31, &[])?,
+            months: Field::parse(fields[4], "month", 1, 12, MONTH_NAMES)?,
+            weekdays,
+        };
+        // NOTE: Only a day of month that no selected month has, like
+        // `30 2`, can never fire. 2000 is a leap year so Feb 29 counts.
+        let restricted_days = !schedule.days.star && schedule.weekdays.star;
+        let any_date = schedule.months.values.iter().any(|month| {
+            schedule
+                .days
+                .values
+                .iter()
+                .any(|day| NaiveDate::from_ymd_opt(2000, *month, *day).is_some())
+        });
+        if restricted_days && !any_date {
+            return Err(CommandError::new(format!(
+                "Cron expression {:?} never fires",
+                s
+            )));
+        }
+        Ok(schedule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, NaiveDateTime};
+
+    /// US Eastern time with the 2024 daylight saving rules. Clocks go
+    /// forward at 2:00 on March 10th and back at 2:00 on November 3rd.
+    #[derive(Clone, Debug)]
+    struct Eastern;
+
+    const EST: i32 = -5 * 3600;
+    const EDT: i32 = -4 * 3600;
+
+    impl TimeZone for Eastern {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Eastern {
+            Eastern
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            // Like chrono's Local this lists standard time first.
+            let valid: Vec<FixedOffset> = [EST, EDT]
+                .iter()
+                .map(|secs| FixedOffset::east_opt(*secs).unwrap())
+                .filter(|offset| {
+                    let utc = *local - Duration::seconds(offset.local_minus_utc() as i64);
+                    self.offset_from_utc_datetime(&utc) == *offset
+                })
+                .collect();
+            match valid.len() {
+                0 => LocalResult::None,
+                1 => LocalResult::Single(valid[0]),
+                _ => LocalResult::Ambiguous(valid[0], valid[1]),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let utc_at = |m, d, h| {
+                NaiveDate::from_ymd_opt(2024, m, d)
+                    .unwrap()
+                    .and_hms_opt(h, 0, 0)
+                    .unwrap()
+            };
+            let summer = *utc >= utc_at(3, 10, 7) && *utc < utc_at(11, 3, 6);
+            FixedOffset::east_opt(if summer { EDT } else { EST }).unwrap()
+        }
+    }
+
+    fn cron(spec: &str) -> CronSchedule {
+        spec.parse().unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    /// Returns the next `n` runs after `start` in the given zone's time,
+    /// formatted as RFC 3339.
+    fn runs<Tz: TimeZone>(schedule: &CronSchedule, start: DateTime<Tz>, n: usize) -> Vec<String> {
+        let mut runs = Vec::new();
+        let mut t = start;
+        for _ in 0..n {
+            t = schedule.next_after(&t).unwrap();
+            runs.push(t.fixed_offset().to_rfc3339());
+        }
+        runs
+    }
+
+    fn eastern(s: &str) -> DateTime<Eastern> {
+        utc(s).with_timezone(&Eastern)
+    }
+
+    #[test]
+    fn fields_accept_lists_ranges_steps_and_names() {
+        let schedule = cron("*/20 9-17/4 * jan-mar mon,wed");
+        assert_eq!(schedule.seconds.values, vec![0]);
+        assert_eq!(schedule.minutes.values, vec![0, 20, 40]);
+        assert_eq!(schedule.hours.values, vec![9, 13, 17]);
+        assert_eq!(schedule.months.values, vec![1, 2, 3]);
+        assert_eq!(schedule.weekdays.values, vec![1, 3]);
+        assert_eq!(cron("5/15 * * * * *").seconds.values, vec![5, 20, 35, 50]);
+        assert_eq!(cron("0 0 * * 7").weekdays.values, vec![0]);
+        assert_eq!(cron("0 0 * * 5-7").weekdays.values, vec![0, 5, 6]);
+        assert_eq!(cron("0 0 * * SUN").weekdays.values, vec![0]);
+    }
+
+    #[test]
+    fn bad_expressions_are_rejected() {
+        for spec in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * foo *",
+            "@fortnightly",
+            "0 0 30 2 *",
+        ] {
+            assert!(spec.parse::<CronSchedule>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn aliases_expand_to_their_schedules() {
+        let start = utc("2024-05-15T10:30:00+00:00");
+        let next = |spec: &str| runs(&cron(spec), start, 1).remove(0);
+        assert_eq!(next("@yearly"), "2025-01-01T00:00:00+00:00");
+        assert_eq!(next("@annually"), "2025-01-01T00:00:00+00:00");
+        assert_eq!(next("@monthly"), "2024-06-01T00:00:00+00:00");
+        // May 19th 2024 is a Sunday.
+        assert_eq!(next("@weekly"), "2024-05-19T00:00:00+00:00");
+        assert_eq!(next("@daily"), "2024-05-16T00:00:00+00:00");
+        assert_eq!(next("@midnight"), "2024-05-16T00:00:00+00:00");
+        assert_eq!(next("@hourly"), "2024-05-15T11:00:00+00:00");
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_restricted() {
+        // The 13th and every Friday. May 2024 starts on a Wednesday.
+        let start = utc("2024-05-01T00:00:00+02:00");
+        assert_eq!(
+            runs(&cron("0 12 13 * fri"), start, 3),
+            vec![
+                "2024-05-03T12:00:00+02:00",
+                "2024-05-10T12:00:00+02:00",
+                "2024-05-13T12:00:00+02:00",
+            ]
+        );
+        // With the day of week left as * only the 13th matches.
+        assert_eq!(
+            runs(&cron("0 12 13 * *"), start, 1),
+            vec!["2024-05-13T12:00:00+02:00"]
+        );
+    }
+
+    #[test]
+    fn skipped_times_fire_once_the_clock_has_jumped_forward() {
+        let start = eastern("2024-03-10T01:00:00-05:00");
+        assert_eq!(
+            runs(&cron("30 2 * * *"), start, 2),
+            vec!["2024-03-10T03:30:00-04:00", "2024-03-11T02:30:00-04:00"]
+        );
+        assert_eq!(
+            runs(&cron("0 * * * *"), start, 3),
+            vec![
+                "2024-03-10T03:00:00-04:00",
+                "2024-03-10T04:00:00-04:00",
+                "2024-03-10T05:00:00-04:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_repeated_hour_fires_once_for_a_fixed_hour() {
+        let start = eastern("2024-11-03T00:00:00-04:00");
+        assert_eq!(
+            runs(&cron("30 1 * * *"), start, 2),
+            vec!["2024-11-03T01:30:00-04:00", "2024-11-04T01:30:00-05:00"]
+        );
+    }
+
+    #[test]
+    fn a_repeated_hour_fires_twice_when_every_hour_runs() {
+        let start = eastern("2024-11-03T00:45:00-04:00");
+        assert_eq!(
+            runs(&cron("*/30 * * * *"), start, 6),
+            vec![
+                "2024-11-03T01:00:00-04:00",
+                "2024-11-03T01:30:00-04:00",
+                "2024-11-03T01:00:00-05:00",
+                "2024-11-03T01:30:00-05:00",
+                "2024-11-03T02:00:00-05:00",
+                "2024-11-03T02:30:00-05:00",
+            ]
+        );
+    }
+}
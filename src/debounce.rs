@@ -0,0 +1,202 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use error::CommandError;
+
+/// Which edge of a burst of events fires the command.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Edge {
+    /// Fire on the first event then stay quiet until the burst ends.
+    Leading,
+    /// Fire once the burst has been quiet for the whole period.
+    Trailing,
+    /// Fire on the first event and again when the burst ends if anything
+    /// else arrived in the meantime.
+    Both,
+}
+
+impl FromStr for Edge {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Edge, CommandError> {
+        match s.trim().to_lowercase().as_str() {
+            "leading" => Ok(Edge::Leading),
+            "trailing" => Ok(Edge::Trailing),
+            "both" => Ok(Edge::Both),
+            other => Err(CommandError::new(format!(
+                "Unknown debounce edge {}: expected one of leading, trailing or both",
+                other
+            ))),
+        }
+    }
+}
+
+/// Collapses bursts of change events into batches.
+///
+/// The debouncer doesn't read the clock itself. Callers pass the current
+/// instant in and use `deadline` to know when to call `poll` so it can be
+/// driven by any event source.
+pub struct Debouncer {
+    quiet: Duration,
+    edge: Edge,
+    pending: Vec<PathBuf>,
+    // The time of the last event while we are inside a burst.
+    last_event: Option<Instant>,
+    // Whether events arrived that the trailing edge should fire for.
+    dirty: bool,
+}
+
+impl Debouncer {
+    pub fn new(quiet: Duration, edge: Edge) -> Debouncer {
+        Debouncer {
+            quiet,
+            edge,
+            pending: Vec::new(),
+            last_event: None,
+            dirty: false,
+        }
+    }
+
+    fn add_paths(&mut self, paths: Vec<PathBuf>) {
+        for p in paths {
+            if !self.pending.contains(&p) {
+                self.pending.push(p);
+            }
+        }
+    }
+
+    /// Records a change event. Returns a batch to run immediately if this
+    /// event fires the leading edge.
+    pub fn push(&mut self, now: Instant, paths: Vec<PathBuf>) -> Option<Vec<PathBuf>> {
+        let in_burst = self.last_event.is_some();
+        self.last_event = Some(now);
+        self.add_paths(paths);
+        if self.quiet == Duration::from_secs(0) {
+            self.last_event = None;
+            return Some(self.take());
+        }
+        if !in_burst && self.edge != Edge::Trailing {
+            return Some(self.take());
+        }
+        self.dirty = true;
+        None
+    }
+
+    /// Returns when `poll` should next be called or None if there is no
+    /// burst in progress.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.last_event.map(|t| t + self.quiet)
+    }
+
+    /// Ends the current burst if it has been quiet long enough. Returns the
+    /// batch to run if this fires the trailing edge.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<PathBuf>> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => {
+                self.last_event = None;
+                if self.dirty && self.edge != Edge::Leading {
+                    Some(self.take())
+                } else {
+                    self.take();
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn take(&mut self) -> Vec<PathBuf> {
+        self.dirty = false;
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUIET: Duration = Duration::from_millis(100);
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn leading_fires_once_per_burst() {
+        let start = Instant::now();
+        let mut d = Debouncer::new(QUIET, Edge::Leading);
+        assert_eq!(d.push(start, paths(&["a"])), Some(paths(&["a"])));
+        assert_eq!(d.push(start + ms(50), paths(&["b"])), None);
+        assert_eq!(d.push(start + ms(120), paths(&["c"])), None);
+        // The burst is still going until 100ms after the last event.
+        assert_eq!(d.poll(start + ms(200)), None);
+        assert_eq!(d.deadline(), Some(start + ms(220)));
+        assert_eq!(d.poll(start + ms(220)), None);
+        assert_eq!(d.deadline(), None);
+        // Paths from the finished burst don't leak into the next one.
+        assert_eq!(d.push(start + ms(300), paths(&["d"])), Some(paths(&["d"])));
+    }
+
+    #[test]
+    fn trailing_fires_after_the_quiet_period() {
+        let start = Instant::now();
+        let mut d = Debouncer::new(QUIET, Edge::Trailing);
+        assert_eq!(d.push(start, paths(&["a"])), None);
+        assert_eq!(d.push(start + ms(60), paths(&["b"])), None);
+        assert_eq!(d.poll(start + ms(159)), None);
+        assert_eq!(d.deadline(), Some(start + ms(160)));
+        assert_eq!(d.poll(start + ms(160)), Some(paths(&["a", "b"])));
+        assert_eq!(d.deadline(), None);
+        assert_eq!(d.poll(start + ms(500)), None);
+    }
+
+    #[test]
+    fn both_fires_at_the_end_only_when_the_burst_was_dirty() {
+        let start = Instant::now();
+        let mut d = Debouncer::new(QUIET, Edge::Both);
+        assert_eq!(d.push(start, paths(&["a"])), Some(paths(&["a"])));
+        assert_eq!(d.poll(start + ms(100)), None);
+
+        let start = start + ms(200);
+        assert_eq!(d.push(start, paths(&["a"])), Some(paths(&["a"])));
+        assert_eq!(d.push(start + ms(30), paths(&["b"])), None);
+        assert_eq!(d.poll(start + ms(130)), Some(paths(&["b"])));
+    }
+
+    #[test]
+    fn duplicate_paths_are_merged_into_one_batch() {
+        let start = Instant::now();
+        let mut d = Debouncer::new(QUIET, Edge::Trailing);
+        d.push(start, paths(&["a"]));
+        d.push(start + ms(10), paths(&["b", "a"]));
+        d.push(start + ms(20), paths(&["a", "b"]));
+        assert_eq!(d.poll(start + ms(120)), Some(paths(&["a", "b"])));
+    }
+
+    #[test]
+    fn no_quiet_period_fires_every_event() {
+        let start = Instant::now();
+        let mut d = Debouncer::new(Duration::from_secs(0), Edge::Trailing);
+        assert_eq!(d.push(start, paths(&["a"])), Some(paths(&["a"])));
+        assert_eq!(d.push(start, paths(&["a"])), Some(paths(&["a"])));
+        assert_eq!(d.deadline(), None);
+    }
+}
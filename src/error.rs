@@ -14,6 +14,7 @@
 use std::fmt;
 use std::io;
 
+use glob;
 use notify;
 
 #[derive(Debug)]
@@ -33,6 +34,8 @@ impl fmt::Display for CommandError {
     }
 }
 
+impl std::error::Error for CommandError {}
+
 impl From<notify::Error> for CommandError {
     fn from(e: notify::Error) -> CommandError {
         CommandError::new(format!("{}", e))
@@ -44,3 +47,9 @@ impl From<io::Error> for CommandError {
         CommandError::new(format!("IO: {}", e))
     }
 }
+
+impl From<glob::PatternError> for CommandError {
+    fn from(e: glob::PatternError) -> CommandError {
+        CommandError::new(format!("Invalid path pattern: {}", e))
+    }
+}
@@ -11,8 +11,12 @@
 //  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
+use std::str::FromStr;
+
 use notify::DebouncedEvent;
 
+use error::CommandError;
+
 #[derive(PartialEq, Clone)]
 pub enum WatchEventType {
     Touched,
@@ -21,6 +25,52 @@ pub enum WatchEventType {
     Ignore,
 }
 
+/// The kinds of filesystem event a user can select with `--on`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum EventKind {
+    Create,
+    Write,
+    Remove,
+    Rename,
+    Chmod,
+}
+
+impl EventKind {
+    /// Returns the kind of a debounced event or None for notices, rescans
+    /// and errors.
+    pub fn of(evt: &DebouncedEvent) -> Option<EventKind> {
+        match evt {
+            DebouncedEvent::Create(_) => Some(EventKind::Create),
+            DebouncedEvent::Write(_) => Some(EventKind::Write),
+            DebouncedEvent::Remove(_) => Some(EventKind::Remove),
+            DebouncedEvent::Rename(_, _) => Some(EventKind::Rename),
+            DebouncedEvent::Chmod(_) => Some(EventKind::Chmod),
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Rescan
+            | DebouncedEvent::Error(_, _) => None,
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<EventKind, CommandError> {
+        match s.trim().to_lowercase().as_str() {
+            "create" => Ok(EventKind::Create),
+            "write" => Ok(EventKind::Write),
+            "remove" => Ok(EventKind::Remove),
+            "rename" => Ok(EventKind::Rename),
+            "chmod" => Ok(EventKind::Chmod),
+            other => Err(CommandError::new(format!(
+                "Unknown event kind {}: expected one of create, write, remove, rename or chmod",
+                other
+            ))),
+        }
+    }
+}
+
 pub fn get_file(evt: &DebouncedEvent) -> Option<&std::path::PathBuf> {
     match evt {
         DebouncedEvent::NoticeWrite(b)
@@ -34,6 +84,24 @@ pub fn get_file(evt: &DebouncedEvent) -> Option<&std::path::PathBuf> {
     }
 }
 
+/// Returns every path an event refers to. Renames report both the source and
+/// the destination.
+pub fn get_paths(evt: &DebouncedEvent) -> Vec<&std::path::PathBuf> {
+    match evt {
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        _ => get_file(evt).into_iter().collect(),
+    }
+}
+
+/// Returns true for the early notice events that notify sends before the
+/// debounced event for the same path.
+pub fn is_notice(evt: &DebouncedEvent) -> bool {
+    matches!(
+        evt,
+        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_)
+    )
+}
+
 impl From<DebouncedEvent> for WatchEventType {
     fn from(e: DebouncedEvent) -> WatchEventType {
         match e {
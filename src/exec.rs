@@ -11,6 +11,7 @@
 //  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
+use std::io;
 use std::process::{Child, Command, Stdio};
 use std::thread;
 use std::time::Duration;
@@ -18,6 +19,37 @@ use std::time::Duration;
 use error::CommandError;
 use traits::Process;
 
+/// The signal sent to a running command when no other was requested.
+#[cfg(unix)]
+pub const DEFAULT_SIGNAL: i32 = libc::SIGHUP;
+#[cfg(not(unix))]
+pub const DEFAULT_SIGNAL: i32 = 1;
+
+/// Parses a signal name like `HUP`, `SIGUSR1` or a signal number.
+#[cfg(unix)]
+pub fn parse_signal(name: &str) -> Result<i32, CommandError> {
+    if let Ok(num) = name.parse::<i32>() {
+        return Ok(num);
+    }
+    let upper = name.trim().to_uppercase();
+    match upper.strip_prefix("SIG").unwrap_or(&upper) {
+        "HUP" => Ok(libc::SIGHUP),
+        "INT" => Ok(libc::SIGINT),
+        "QUIT" => Ok(libc::SIGQUIT),
+        "KILL" => Ok(libc::SIGKILL),
+        "TERM" => Ok(libc::SIGTERM),
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        _ => Err(CommandError::new(format!("Unknown signal {}", name))),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn parse_signal(name: &str) -> Result<i32, CommandError> {
+    name.parse::<i32>()
+        .map_err(|_| CommandError::new(format!("Unknown signal {}", name)))
+}
+
 fn env_var_to_tuple(var: &str) -> (String, String) {
     let mut vs = var.split('=');
     if let Some(name) = vs.next() {
@@ -32,6 +64,8 @@ fn env_var_to_tuple(var: &str) -> (String, String) {
 pub struct CancelableProcess {
     cmd: String,
     env: Option<Vec<String>>,
+    extra_env: Vec<(String, String)>,
+    extra_args: Vec<String>,
     exec: Option<Command>,
     handle: Option<Child>,
 }
@@ -41,32 +75,54 @@ impl CancelableProcess {
         Self {
             cmd: cmd.to_string(),
             env,
+            extra_env: Vec::new(),
+            extra_args: Vec::new(),
             exec: None,
             handle: None,
         }
     }
 
-    fn create_command(cmd: &str, env: &Option<Vec<String>>) -> Result<Command, CommandError> {
+    /// Sets environment variables describing the trigger for the next
+    /// spawn of the command.
+    pub fn set_extra_env(&mut self, vars: Vec<(String, String)>) {
+        self.extra_env = vars;
+    }
+
+    /// Sets arguments appended to the command for the next spawn.
+    pub fn set_extra_args(&mut self, args: Vec<String>) {
+        self.extra_args = args;
+    }
+
+    fn create_command(
+        cmd: &str,
+        env: &Option<Vec<String>>,
+        extra_env: &[(String, String)],
+        extra_args: &[String],
+    ) -> Result<Command, CommandError> {
         let args = cmd
             .split(' ')
             .filter(|s| !s.is_empty())
             .collect::<Vec<&str>>();
-        if args.len() < 1 {
+        if args.is_empty() {
             return Err(CommandError::new("Empty command string passed in"));
         }
         let mut exec = Command::new(args[0]);
         if args.len() > 1 {
             exec.args(&args[1..]);
         }
+        exec.args(extra_args);
         exec.stdout(Stdio::inherit());
         exec.stderr(Stdio::inherit());
-        if let &Some(ref env_vars) = env {
+        if let Some(env_vars) = env {
             for var in env_vars {
                 let tpl = env_var_to_tuple(var);
                 exec.env(tpl.0, tpl.1);
             }
         }
-        return Ok(exec);
+        for (name, val) in extra_env {
+            exec.env(name, val);
+        }
+        Ok(exec)
     }
 
     pub fn block(&mut self) -> Result<i32, CommandError> {
@@ -76,18 +132,31 @@ impl CancelableProcess {
             self.handle = None;
             Ok(code)
         } else {
-            let mut exec = Self::create_command(&self.cmd, &self.env)?;
-            return match exec.output() {
+            let mut exec =
+                Self::create_command(&self.cmd, &self.env, &self.extra_env, &self.extra_args)?;
+            match exec.output() {
                 Ok(out) => match out.status.code() {
                     Some(val) => Ok(val),
                     None => Ok(0),
                 },
                 // TODO(jeremy): We should not swallow this error.
                 Err(_) => Err(CommandError::new("Error running command")),
-            };
+            }
         }
     }
 
+    /// Waits for the spawned command to exit and returns true if it
+    /// succeeded. Unlike `block` a command killed by a signal has failed.
+    pub fn wait_success(&mut self) -> Result<bool, CommandError> {
+        let success = match self.handle {
+            Some(ref mut h) => h.wait()?.success(),
+            None => return Err(CommandError::new("Command was not started")),
+        };
+        self.exec = None;
+        self.handle = None;
+        Ok(success)
+    }
+
     pub fn is_success(&mut self) -> bool {
         match self.block() {
             Ok(code) => code == 0,
@@ -108,8 +177,36 @@ impl CancelableProcess {
         })
     }
 
+    /// Returns true if the spawned command hasn't exited yet. Unlike `check`
+    /// this never blocks.
+    pub fn is_running(&mut self) -> Result<bool, CommandError> {
+        Ok(match self.handle {
+            Some(ref mut h) => h.try_wait()?.is_none(),
+            None => false,
+        })
+    }
+
+    /// Sends a signal to the spawned command if it is still running.
+    #[cfg(unix)]
+    pub fn signal(&mut self, sig: i32) -> Result<(), CommandError> {
+        if let Some(ref h) = self.handle {
+            if unsafe { libc::kill(h.id() as libc::pid_t, sig) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn signal(&mut self, _sig: i32) -> Result<(), CommandError> {
+        Err(CommandError::new(
+            "Signals are not supported on this platform",
+        ))
+    }
+
     pub fn spawn(&mut self) -> Result<(), CommandError> {
-        let mut exec = Self::create_command(&self.cmd, &self.env)?;
+        let mut exec =
+            Self::create_command(&self.cmd, &self.env, &self.extra_env, &self.extra_args)?;
         let handle = exec.spawn()?;
         self.exec = Some(exec);
         self.handle = Some(handle);
@@ -11,87 +11,372 @@
 //  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
-use std::path::Path;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use glob;
-use notify::{watcher, RecursiveMode, Watcher};
+use notify::DebouncedEvent;
 
+use debounce::{Debouncer, Edge};
 use error::CommandError;
-use events::WatchEventType;
+use events::{EventKind, WatchEventType};
 use exec::CancelableProcess;
-use traits::Process;
+use filter::{absolute, PathFilter, PathPattern};
+use git::{GitFiles, GIT_BATCH_WINDOW};
+use hash::ContentCache;
+use keys::KeyFilter;
+use pause::{PauseGate, PAUSE_CHECK_INTERVAL};
+use poll::{FallbackWatcher, PollWatcher};
+use settle::Settler;
+use traits::{Process, WatchBackend};
+use watches::{glob_base, parse_target, WatchDepth, WatchSet};
+
+/// The environment variable holding the newline separated paths that
+/// changed since the command was last started.
+pub const CHANGED_PATHS_VAR: &str = "RUNWHEN_CHANGED_PATHS";
+
+/// How long to wait between checks on a busy command while changes are
+/// queued.
+const BUSY_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What to do with changes that arrive while the command is still running.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BusyPolicy {
+    /// Kill the command and start it again.
+    Restart,
+    /// Let the command finish and then run it once more.
+    Queue,
+    /// Drop the changes.
+    Ignore,
+    /// Send the command a signal and let it handle the change.
+    Signal,
+}
+
+impl FromStr for BusyPolicy {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<BusyPolicy, CommandError> {
+        match s.trim().to_lowercase().as_str() {
+            "restart" => Ok(BusyPolicy::Restart),
+            "queue" => Ok(BusyPolicy::Queue),
+            "ignore" => Ok(BusyPolicy::Ignore),
+            "signal" => Ok(BusyPolicy::Signal),
+            other => Err(CommandError::new(format!(
+                "Unknown busy policy {}: expected one of restart, queue, ignore or signal",
+                other
+            ))),
+        }
+    }
+}
+
+/// How the watch trigger learns about filesystem changes.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Backend {
+    /// The platform's native notification api. inotify, FSEvents etc.
+    Native,
+    /// Periodically stat every watched path.
+    Poll,
+}
+
+impl FromStr for Backend {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Backend, CommandError> {
+        match s.trim().to_lowercase().as_str() {
+            "native" => Ok(Backend::Native),
+            "poll" => Ok(Backend::Poll),
+            other => Err(CommandError::new(format!(
+                "Unknown backend {}: expected one of native or poll",
+                other
+            ))),
+        }
+    }
+}
+
+/// Options controlling what the watch trigger fires on.
+pub struct WatchOptions<'a> {
+    pub include: Vec<&'a str>,
+    pub exclude: Vec<&'a str>,
+    pub vcs_ignore: bool,
+    pub extensions: Vec<&'a str>,
+    /// When set only these kinds of event fire and `method` is ignored.
+    pub kinds: Option<Vec<EventKind>>,
+    pub method: WatchEventType,
+    /// Only fire for files whose content actually changed.
+    pub content_hash: bool,
+    /// Files larger than this are never hashed and always count as changed.
+    pub hash_max_size: u64,
+    /// Where to persist content hashes between runs.
+    pub hash_cache: Option<&'a str>,
+    /// Config file keys like `config.toml#database.url`. Changes to those
+    /// files only fire when one of their keys changes value.
+    pub config_keys: Vec<&'a str>,
+    /// How long events must be quiet before a batch fires.
+    pub debounce: Option<Duration>,
+    pub edge: Edge,
+    pub on_busy: BusyPolicy,
+    /// The signal sent to a busy command for `BusyPolicy::Signal`.
+    pub busy_signal: i32,
+    /// Run the command at startup after this delay. None waits for the
+    /// first change.
+    pub initial_run: Option<Duration>,
+    /// Ignore changes while the command runs and for this long after it
+    /// exits so a command writing into the watched tree doesn't trigger
+    /// itself.
+    pub ignore_own_changes: Option<Duration>,
+    /// Commands to run for changes to particular paths instead of the
+    /// default command.
+    pub routes: Vec<Route<'a>>,
+    /// A file, or `-` for stdin, listing more paths to watch.
+    pub files_from: Option<&'a str>,
+    /// Re-read `files_from` when it changes.
+    pub reload_files_from: bool,
+    /// How deep to watch paths that don't give their own depth.
+    pub depth: WatchDepth,
+    pub backend: Backend,
+    /// Only fire for a file once its size and mtime have been stable this
+    /// long.
+    pub settle: Option<Duration>,
+    /// The repository root when only files git tracks, or untracked files
+    /// it doesn't ignore, should fire.
+    pub git: Option<&'a str>,
+    /// Hold changes back while any of these paths exist.
+    pub pause_while: Vec<&'a str>,
+    /// Wait for watched paths that don't exist yet instead of failing.
+    pub allow_missing: bool,
+    /// Run the command when a missing watched path is created.
+    pub fire_on_create: bool,
+    /// Run the command when the watcher had to rescan because it lost
+    /// events.
+    pub fire_on_rescan: bool,
+    /// How often the poll backend scans the watched paths. Also used for
+    /// paths the native backend can't watch.
+    pub poll_interval: Duration,
+    /// Deprecated throttle. Treated as a debounce firing on both edges when
+    /// no debounce was given.
+    pub poll: Option<Duration>,
+}
+
+/// Runs a command for the changes to paths matching a pattern.
+pub struct Route<'a> {
+    pub pattern: &'a str,
+    pub cmd: &'a str,
+}
+
+impl<'a> Route<'a> {
+    /// Parses a `pattern => command` rule.
+    pub fn parse(spec: &'a str) -> Result<Route<'a>, CommandError> {
+        match spec.split_once("=>") {
+            Some((pattern, cmd)) if !pattern.trim().is_empty() && !cmd.trim().is_empty() => {
+                Ok(Route {
+                    pattern: pattern.trim(),
+                    cmd: cmd.trim(),
+                })
+            }
+            _ => Err(CommandError::new(format!(
+                "Invalid route {}: expected pattern => command",
+                spec
+            ))),
+        }
+    }
+}
 
 pub struct FileProcess<'a> {
-    cmd: &'a str,
+    /// The command for changes no route matches.
+    cmd: Option<&'a str>,
     env: Option<Vec<String>>,
     files: Vec<&'a str>,
-    exclude: Option<Vec<&'a str>>,
-    method: WatchEventType,
-    poll: Option<Duration>,
+    opts: WatchOptions<'a>,
 }
 
 impl<'a> FileProcess<'a> {
     pub fn new(
-        cmd: &'a str,
+        cmd: Option<&'a str>,
         env: Option<Vec<String>>,
         file: Vec<&'a str>,
-        exclude: Option<Vec<&'a str>>,
-        method: WatchEventType,
-        poll: Option<Duration>,
+        opts: WatchOptions<'a>,
     ) -> FileProcess<'a> {
         FileProcess {
             cmd,
             env,
-            method,
-            poll,
-            exclude,
+            opts,
             files: file,
         }
     }
 }
 
+/// Settings for how the command is run as changes arrive.
+#[derive(Clone, Copy)]
+struct RunOptions {
+    on_busy: BusyPolicy,
+    busy_signal: i32,
+    initial_run: Option<Duration>,
+    ignore_own_changes: Option<Duration>,
+}
+
 fn watch_for_change_events(
-    ch: Receiver<()>,
+    ch: Receiver<Vec<PathBuf>>,
     cmd: String,
     env: Option<Vec<String>>,
-    poll: Option<Duration>,
+    mut debouncer: Debouncer,
+    run: RunOptions,
 ) {
-    let copied_env = env.and_then(|v| {
-        Some(
-            v.iter()
-                .cloned()
-                .map(|s| String::from(s))
-                .collect::<Vec<String>>(),
-        )
-    });
-    let mut exec = CancelableProcess::new(&cmd, copied_env);
-    println!("Spawning command");
-    exec.spawn().expect("Failed to start command");
-    println!("Starting watch loop");
-    run_loop_step(&mut exec);
+    let RunOptions {
+        on_busy,
+        busy_signal,
+        initial_run,
+        ignore_own_changes,
+    } = run;
+    let mut exec = CancelableProcess::new(&cmd, env);
+    if let Some(delay) = initial_run {
+        thread::sleep(delay);
+        // The first run covers anything that changed while we waited.
+        while ch.try_recv().is_ok() {}
+        println!("Spawning command");
+        exec.spawn().expect("Failed to start command");
+    }
     println!("Waiting for first change event");
-    if let Some(poll) = poll {
-        let mut poll_time = Instant::now();
-        loop {
-            let _ = ch.recv().expect("Channel was closed!!!");
-            let elapsed = Instant::now().duration_since(poll_time);
-            poll_time = Instant::now();
-            if elapsed >= poll {
-                run_loop_step(&mut exec);
-                continue;
+    let mut queued: Option<Vec<PathBuf>> = None;
+    // Changes before this are assumed to be made by the command itself.
+    let mut own_changes_until: Option<Instant> = None;
+    let mut own_changes = |exec: &mut CancelableProcess| -> bool {
+        let grace = match ignore_own_changes {
+            Some(grace) => grace,
+            None => return false,
+        };
+        let now = Instant::now();
+        if exec.is_running().unwrap_or(false) {
+            own_changes_until = Some(now + grace);
+            return true;
+        }
+        own_changes_until.is_some_and(|until| now < until)
+    };
+    loop {
+        let mut wake = debouncer.deadline();
+        // Keep an eye on a running command so we know when its grace period
+        // starts.
+        if queued.is_some() || own_changes(&mut exec) {
+            let check = Instant::now() + BUSY_CHECK_INTERVAL;
+            wake = Some(wake.map_or(check, |w| w.min(check)));
+        }
+        // When the sender goes away there are no more changes to wait for.
+        let paths = match wake {
+            Some(wake) => match ch.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+                Ok(paths) => Some(paths),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match ch.recv() {
+                Ok(paths) => Some(paths),
+                Err(_) => return,
+            },
+        };
+        let batch = match paths {
+            Some(_) if own_changes(&mut exec) => {
+                println!("Ignoring changes made by the command");
+                None
+            }
+            Some(paths) => debouncer.push(Instant::now(), paths),
+            None => debouncer.poll(Instant::now()),
+        };
+        // NOTE: If we can't tell whether the command is running we
+        // treat it as finished so a change is never lost.
+        let running = exec.is_running().unwrap_or(false);
+        if let Some(batch) = batch {
+            match on_busy {
+                BusyPolicy::Restart => run_batch(&mut exec, batch),
+                _ if !running => run_batch(&mut exec, batch),
+                BusyPolicy::Queue => {
+                    println!("Command is still running, queueing change");
+                    let pending = queued.get_or_insert_with(Vec::new);
+                    for p in batch {
+                        if !pending.contains(&p) {
+                            pending.push(p);
+                        }
+                    }
+                }
+                BusyPolicy::Ignore => println!("Command is still running, ignoring change"),
+                BusyPolicy::Signal => {
+                    println!("Command is still running, sending signal {}", busy_signal);
+                    if let Err(err) = exec.signal(busy_signal) {
+                        println!("Failed to signal command");
+                        println!("{:?}", err);
+                    }
+                }
             }
         }
-    } else {
-        loop {
-            let _ = ch.recv().expect("Channel was closed!!!");
-            run_loop_step(&mut exec);
+        if queued.is_some() && !exec.is_running().unwrap_or(false) {
+            if let Some(batch) = queued.take() {
+                run_batch(&mut exec, batch);
+            }
+        }
+    }
+}
+
+/// Sends each path in a batch to every route whose pattern matches it and
+/// the paths no route matched to the default command if there is one.
+fn route_changes(
+    ch: Receiver<Vec<PathBuf>>,
+    routes: Vec<(PathPattern, Sender<Vec<PathBuf>>)>,
+    default: Option<Sender<Vec<PathBuf>>>,
+) {
+    for batch in ch.iter() {
+        // An empty batch means something changed but we don't know what so
+        // every command has to run.
+        if batch.is_empty() {
+            for (_, route) in routes.iter() {
+                route.send(Vec::new()).expect("Channel was closed!!!");
+            }
+            if let Some(ref default) = default {
+                default.send(Vec::new()).expect("Channel was closed!!!");
+            }
+            continue;
+        }
+        let mut unmatched = Vec::new();
+        let mut routed: Vec<Vec<PathBuf>> = routes.iter().map(|_| Vec::new()).collect();
+        for path in batch {
+            let mut matched = false;
+            for (idx, (pattern, _)) in routes.iter().enumerate() {
+                if pattern.is_match(&path) {
+                    routed[idx].push(path.clone());
+                    matched = true;
+                }
+            }
+            if !matched {
+                unmatched.push(path);
+            }
+        }
+        for ((_, route), paths) in routes.iter().zip(routed) {
+            if !paths.is_empty() {
+                route.send(paths).expect("Channel was closed!!!");
+            }
+        }
+        if let Some(ref default) = default {
+            if !unmatched.is_empty() {
+                default.send(unmatched).expect("Channel was closed!!!");
+            }
         }
     }
 }
 
+fn run_batch(exec: &mut CancelableProcess, batch: Vec<PathBuf>) {
+    println!("{} path(s) changed", batch.len());
+    exec.set_extra_env(vec![(
+        CHANGED_PATHS_VAR.to_string(),
+        batch
+            .iter()
+            .map(|p| p.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )]);
+    run_loop_step(exec);
+}
+
 fn run_loop_step(exec: &mut CancelableProcess) {
     // We always want to check on our process each iteration of the loop.
     // set signal to false so we won't trigger on the
@@ -104,81 +389,432 @@ fn run_loop_step(exec: &mut CancelableProcess) {
     }
 }
 
+/// Reads newline or NUL separated watch targets from a file or from stdin
+/// if the path is `-`.
+fn read_files_from(source: &str) -> Result<Vec<String>, CommandError> {
+    let mut contents = String::new();
+    if source == "-" {
+        io::stdin().read_to_string(&mut contents)?;
+    } else {
+        File::open(source)?.read_to_string(&mut contents)?;
+    }
+    let sep = if contents.contains('\0') { '\0' } else { '\n' };
+    Ok(contents
+        .split(sep)
+        .map(|l| l.trim_end_matches('\r'))
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Returns the watch targets given on the command line followed by the ones
+/// in the `files_from` list.
+fn target_specs(files: &[&str], files_from: Option<&str>) -> Result<Vec<String>, CommandError> {
+    let mut specs: Vec<String> = files.iter().map(|f| f.to_string()).collect();
+    if let Some(source) = files_from {
+        specs.extend(read_files_from(source)?);
+    }
+    Ok(specs)
+}
+
+/// Turns the watch target specs into the paths to watch and the filter
+/// for the events they produce.
+fn build_targets(
+    specs: &[String],
+    opts: &WatchOptions,
+) -> Result<(Vec<(PathBuf, WatchDepth)>, PathFilter), CommandError> {
+    let mut targets = Vec::new();
+    let mut plain_roots = Vec::new();
+    let mut globs = Vec::new();
+    for spec in specs.iter() {
+        let (path, depth) = parse_target(spec, opts.depth)?;
+        match glob_base(path) {
+            Some((base, glob_depth)) => {
+                // An explicit depth suffix wins over the one the pattern
+                // implies.
+                let depth = if path.len() < spec.len() {
+                    depth
+                } else {
+                    glob_depth
+                };
+                globs.push((base.clone(), path));
+                targets.push((base, depth));
+            }
+            None => {
+                plain_roots.push(PathBuf::from(path));
+                targets.push((PathBuf::from(path), depth));
+            }
+        }
+    }
+    let roots: Vec<&PathBuf> = targets.iter().map(|(p, _)| p).collect();
+    let mut filter = PathFilter::new(&plain_roots, &opts.include, &opts.exclude)?;
+    for (base, pattern) in globs {
+        filter.add_target_glob(&base, pattern)?;
+    }
+    filter.set_extensions(&opts.extensions);
+    if opts.vcs_ignore {
+        filter.load_vcs_ignores(&roots)?;
+    }
+    Ok((targets, filter))
+}
+
 fn wait_for_fs_events(
-    ch: Sender<()>,
-    method: WatchEventType,
-    files: &Vec<&str>,
-    excluded: &Option<Vec<&str>>,
+    ch: Sender<Vec<PathBuf>>,
+    opts: &WatchOptions,
+    files: &[&str],
 ) -> Result<(), CommandError> {
+    let specs = target_specs(files, opts.files_from)?;
+    // The list file is watched for changes too when it should be reloaded.
+    let list_file = match opts.files_from {
+        Some("-") if opts.reload_files_from => {
+            println!("Can't reload the watch targets from stdin");
+            None
+        }
+        Some(source) if opts.reload_files_from => Some(absolute(Path::new(source))),
+        _ => None,
+    };
+    let (mut targets, mut filter) = build_targets(&specs, opts)?;
     // Notify requires a channel for communication.
     let (tx, rx) = channel();
-    let mut watcher = watcher(tx, Duration::from_secs(1))?;
-    for file in files {
-        // NOTE(jwall): this is necessary because notify::fsEventWatcher panics
-        // if the path doesn't exist. :-(
-        if !Path::new(*file).exists() {
-            return Err(CommandError::new(
-                format!("No such path! {0}", *file).to_string(),
-            ));
-        }
-        watcher.watch(*file, RecursiveMode::Recursive)?;
-        println!("Watching {:?}", *file);
+    let backend: Box<dyn WatchBackend> = match opts.backend {
+        Backend::Native => Box::new(FallbackWatcher::new(
+            tx,
+            Duration::from_secs(1),
+            opts.poll_interval,
+        )?),
+        Backend::Poll => Box::new(PollWatcher::new(tx, opts.poll_interval)),
+    };
+    let mut content = if opts.content_hash {
+        Some(ContentCache::new(opts.hash_max_size, opts.hash_cache)?)
+    } else {
+        None
+    };
+    let mut watches = WatchSet::new(backend, opts.allow_missing, opts.fire_on_create);
+    if let Some(ref list) = list_file {
+        targets.push((list.clone(), WatchDepth::NonRecursive));
     }
-    let mut patterns = Vec::new();
-    if let Some(exclude) = excluded {
-        for ef in exclude.iter() {
-            patterns.push(glob::Pattern::new(*ef).expect("Invalid path pattern"));
-        }
+    watches.sync(&targets)?;
+    if let Some(ref mut content) = content {
+        // Skip directories nothing in would fire anyway, like target.
+        let wanted = |p: &Path| {
+            if p.is_dir() {
+                !p.ends_with(".git") && !filter.is_vcs_ignored(p)
+            } else {
+                filter.is_match(p)
+            }
+        };
+        content.record_targets(&targets, &wanted);
     }
+    let mut keys = if opts.config_keys.is_empty() {
+        None
+    } else {
+        Some(KeyFilter::new(&opts.config_keys)?)
+    };
+    let mut settler = opts.settle.map(Settler::new);
+    let mut git = match opts.git {
+        Some(root) => Some(GitFiles::load(root)?),
+        None => None,
+    };
+    let mut pause_while: Vec<PathBuf> = opts
+        .pause_while
+        .iter()
+        .map(|p| absolute(Path::new(p)))
+        .collect();
+    if let Some(ref git) = git {
+        pause_while.push(git.index_lock());
+    }
+    let mut pause = PauseGate::new(pause_while);
+    // Events already taken off the channel and waiting to be handled.
+    let mut queued: VecDeque<DebouncedEvent> = VecDeque::new();
     loop {
-        let evt: WatchEventType = match rx.recv() {
+        if let Some(held) = pause.release() {
+            ch.send(held).unwrap();
+        }
+        let mut deadline = settler.as_ref().and_then(|s| s.deadline(Instant::now()));
+        if pause.is_holding() {
+            let check = Instant::now() + PAUSE_CHECK_INTERVAL;
+            deadline = Some(deadline.map_or(check, |d| d.min(check)));
+        }
+        let received = match (queued.pop_front(), deadline) {
+            (Some(event), _) => Ok(event),
+            (None, Some(deadline)) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(event) => Ok(event),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Some(ref mut settler) = settler {
+                            let settled = settler.poll(Instant::now());
+                            if !settled.is_empty() {
+                                send_changes(&ch, &mut keys, &mut content, &mut pause, settled);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => Err("Channel was closed".to_string()),
+                }
+            }
+            (None, None) => rx.recv().map_err(|e| e.to_string()),
+        };
+        let (evt, paths): (WatchEventType, Vec<PathBuf>) = match received {
             Ok(event) => {
-                // TODO(jwall): Filter this based on the exclude pattern
-                if let Some(f) = crate::events::get_file(&event) {
-                    for pat in patterns.iter() {
-                        if pat.matches_path(&f) {
-                            continue;
+                if let Some(ref mut git) = git {
+                    if queued.is_empty() {
+                        // The watcher sends each debounced burst at once, so
+                        // take all of it and ask git about its new files
+                        // together.
+                        queued.extend(rx.try_iter());
+                        let burst: Vec<&Path> = crate::events::get_paths(&event)
+                            .into_iter()
+                            .chain(queued.iter().flat_map(crate::events::get_paths))
+                            .map(|p| p.as_path())
+                            .collect();
+                        git.look_up(&burst, |p| {
+                            !filter.matches_rules(p) || filter.is_vcs_ignored(p)
+                        });
+                    }
+                }
+                let mut paths: Vec<PathBuf> = crate::events::get_paths(&event)
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                // Decide this before the watches are updated so the event
+                // that creates a missing target doesn't count as a change in
+                // it.
+                let watched = paths.is_empty() || paths.iter().any(|p| watches.is_watched(p));
+                let appeared = watches.handle_event(&event);
+                if !appeared.is_empty() {
+                    if let Some(appeared) = pause.pass(appeared) {
+                        ch.send(appeared).unwrap();
+                    }
+                }
+                if !watched {
+                    continue;
+                }
+                if let Some(ref list) = list_file {
+                    if paths.iter().any(|p| absolute(p) == *list) {
+                        if !crate::events::is_notice(&event) && list.exists() {
+                            println!("Reloading watch targets from {:?}", list);
+                            // NOTE: A bad list shouldn't stop us
+                            // watching so we keep the old one until it's fixed.
+                            match target_specs(files, opts.files_from)
+                                .and_then(|specs| build_targets(&specs, opts))
+                            {
+                                Ok((mut new_targets, new_filter)) => {
+                                    new_targets.push((list.clone(), WatchDepth::NonRecursive));
+                                    match watches.sync(&new_targets) {
+                                        Ok(()) => filter = new_filter,
+                                        Err(err) => {
+                                            println!("Failed to reload the watch targets");
+                                            println!("{:?}", err);
+                                        }
+                                    }
+                                }
+                                Err(err) => {
+                                    println!("Failed to reload the watch targets");
+                                    println!("{:?}", err);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                }
+                if !crate::events::is_notice(&event) {
+                    for p in paths.iter() {
+                        filter.reload_if_ignore_file(p)?;
+                    }
+                }
+                // Events without a path are errors or rescans and are
+                // handled below.
+                if !paths.is_empty() {
+                    paths.retain(|p| match git {
+                        // Git knows better than our ignore rules which files
+                        // it tracks.
+                        Some(ref mut git) => {
+                            filter.matches_rules(p) && git.is_relevant(p, filter.is_vcs_ignored(p))
                         }
+                        None => filter.is_match(p),
+                    });
+                    if paths.is_empty() {
+                        continue;
                     }
                 }
-                WatchEventType::from(event)
+                let evt = match event {
+                    DebouncedEvent::Rescan => {
+                        println!("The watcher lost events and rescanned the watched paths");
+                        if opts.fire_on_rescan {
+                            WatchEventType::Changed
+                        } else {
+                            WatchEventType::Ignore
+                        }
+                    }
+                    DebouncedEvent::Error(ref err, ref path) => {
+                        match path {
+                            Some(path) => println!("Watch Error for {:?}: {}", path, err),
+                            None => println!("Watch Error: {}", err),
+                        }
+                        WatchEventType::Error
+                    }
+                    _ => match opts.kinds {
+                        Some(ref kinds) => match EventKind::of(&event) {
+                            Some(kind) if kinds.contains(&kind) => WatchEventType::Changed,
+                            _ => WatchEventType::Ignore,
+                        },
+                        None => WatchEventType::from(event),
+                    },
+                };
+                (evt, paths)
             }
             Err(e) => {
                 println!("Watch Error: {}", e);
-                WatchEventType::Error
+                (WatchEventType::Error, Vec::new())
             }
         };
-        match evt {
-            WatchEventType::Ignore | WatchEventType::Error => {
-                // We ignore these.
-                //println!("Event: Ignore");
-            }
-            WatchEventType::Touched => {
-                if method == WatchEventType::Touched {
-                    ch.send(()).unwrap();
-                }
+        let fire = match evt {
+            // We ignore these.
+            WatchEventType::Ignore | WatchEventType::Error => false,
+            WatchEventType::Touched => opts.method == WatchEventType::Touched,
+            WatchEventType::Changed => true,
+        };
+        if !fire {
+            continue;
+        }
+        match settler {
+            // Without paths we can't tell when things settle.
+            Some(ref mut settler) if !paths.is_empty() => settler.push(Instant::now(), paths),
+            _ => send_changes(&ch, &mut keys, &mut content, &mut pause, paths),
+        }
+    }
+}
+
+/// Sends changed paths on to the command, dropping config files whose
+/// watched keys are unchanged and files whose content hasn't changed if we
+/// are hashing content, and holding them back while paused.
+fn send_changes(
+    ch: &Sender<Vec<PathBuf>>,
+    keys: &mut Option<KeyFilter>,
+    content: &mut Option<ContentCache>,
+    pause: &mut PauseGate,
+    mut paths: Vec<PathBuf>,
+) {
+    if let Some(ref mut keys) = keys {
+        if !paths.is_empty() {
+            paths.retain(|p| keys.changed(p));
+            if paths.is_empty() {
+                return;
             }
-            WatchEventType::Changed => {
-                ch.send(()).unwrap();
+        }
+    }
+    if let Some(ref mut cache) = content {
+        if !paths.is_empty() {
+            cache.retain_changed(&mut paths);
+            if paths.is_empty() {
+                return;
             }
         }
     }
+    if let Some(paths) = pause.pass(paths) {
+        ch.send(paths).unwrap();
+    }
 }
 
-impl<'a> Process for FileProcess<'a> {
-    fn run(&mut self) -> Result<(), CommandError> {
-        // TODO(jeremy): Is this sufficent or do we want to ignore
-        // any events that come in while the command is running?
+impl<'a> FileProcess<'a> {
+    /// Starts a thread running `cmd` for the changes sent to the returned
+    /// channel.
+    fn spawn_command(&self, cmd: &str) -> Sender<Vec<PathBuf>> {
         let (tx, rx) = channel();
         thread::spawn({
-            let cmd = self.cmd.to_string();
+            let cmd = cmd.to_string();
             let env = self.env.clone();
-            let poll = self.poll.clone();
+            let debouncer = match (self.opts.debounce, self.opts.poll) {
+                (Some(quiet), _) => Debouncer::new(quiet, self.opts.edge),
+                (None, Some(poll)) => Debouncer::new(poll, Edge::Both),
+                (None, None) if self.opts.git.is_some() => {
+                    Debouncer::new(GIT_BATCH_WINDOW, self.opts.edge)
+                }
+                (None, None) => Debouncer::new(Duration::from_secs(0), self.opts.edge),
+            };
+            let run = RunOptions {
+                on_busy: self.opts.on_busy,
+                busy_signal: self.opts.busy_signal,
+                initial_run: self.opts.initial_run,
+                ignore_own_changes: self.opts.ignore_own_changes,
+            };
             move || {
-                watch_for_change_events(rx, cmd, env, poll);
+                watch_for_change_events(rx, cmd, env, debouncer, run);
             }
         });
-        wait_for_fs_events(tx, self.method.clone(), &self.files, &self.exclude)?;
+        tx
+    }
+}
+
+impl<'a> Process for FileProcess<'a> {
+    fn run(&mut self) -> Result<(), CommandError> {
+        let default = self.cmd.map(|cmd| self.spawn_command(cmd));
+        let tx = if self.opts.routes.is_empty() {
+            default.expect("A command or routes are required")
+        } else {
+            let mut routes = Vec::new();
+            for route in self.opts.routes.iter() {
+                routes.push((
+                    PathPattern::new(route.pattern)?,
+                    self.spawn_command(route.cmd),
+                ));
+            }
+            let (tx, rx) = channel();
+            thread::spawn(move || route_changes(rx, routes, default));
+            tx
+        };
+        wait_for_fs_events(tx, &self.opts, &self.files)?;
         Ok(())
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn a_burst_of_events_runs_the_command_once() {
+        let dir = env::temp_dir().join(format!("runwhen-burst-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("runs.log");
+        let script = dir.join("record.sh");
+        fs::write(
+            &script,
+            format!(
+                "echo \"${}\" | paste -sd, - >> {}\n",
+                CHANGED_PATHS_VAR,
+                log.display()
+            ),
+        )
+        .unwrap();
+        let (tx, rx) = channel();
+        let run = RunOptions {
+            on_busy: BusyPolicy::Queue,
+            busy_signal: 0,
+            initial_run: None,
+            ignore_own_changes: None,
+        };
+        let debouncer = Debouncer::new(Duration::from_millis(200), Edge::Trailing);
+        let cmd = format!("sh {}", script.display());
+        let handle = thread::spawn(move || watch_for_change_events(rx, cmd, None, debouncer, run));
+        for p in ["a.rs", "b.rs", "a.rs", "c.rs"].iter() {
+            tx.send(vec![PathBuf::from(p)]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(600));
+        drop(tx);
+        handle.join().unwrap();
+        // The command runs in the background so give it a moment to write.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut runs = String::new();
+        while runs.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+            runs = fs::read_to_string(&log).unwrap_or_default();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(runs, "a.rs,b.rs,c.rs\n");
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+use error::CommandError;
+use ignore::VcsIgnore;
+
+// NOTE: `*` must not cross a directory boundary or `src/*.rs` would
+// match everything under src. Use `**` to match nested directories.
+pub const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A single glob rule.
+///
+/// Rules without a `/` match any component of a path so `target` or `*.swp`
+/// apply at every depth. Rules containing a `/` are anchored to the watch
+/// root and absolute rules are matched against the full path. A rule that
+/// matches a directory also matches everything below it.
+struct Rule {
+    pattern: Pattern,
+    anchored: bool,
+    absolute: bool,
+}
+
+impl Rule {
+    fn new(pat: &str) -> Result<Rule, CommandError> {
+        let absolute = pat.starts_with('/');
+        let trimmed = pat.trim_start_matches("./");
+        let trimmed = if trimmed.len() > 1 {
+            trimmed.trim_end_matches('/')
+        } else {
+            trimmed
+        };
+        Ok(Rule {
+            pattern: Pattern::new(trimmed)?,
+            anchored: trimmed.contains('/'),
+            absolute,
+        })
+    }
+
+    fn matches(&self, full: &Path, rel: &Path) -> bool {
+        if self.absolute {
+            return full
+                .ancestors()
+                .any(|p| self.pattern.matches_path_with(p, MATCH_OPTIONS));
+        }
+        if self.anchored {
+            return rel
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .any(|p| self.pattern.matches_path_with(p, MATCH_OPTIONS));
+        }
+        rel.iter().any(|c| match c.to_str() {
+            Some(s) => self.pattern.matches_with(s, MATCH_OPTIONS),
+            None => false,
+        })
+    }
+}
+
+/// Decides which paths reported by the watcher should trigger the command.
+///
+/// Paths are matched relative to the watch root they live under. A path
+/// triggers when it matches no exclude rule and, if any include rules were
+/// given, at least one include rule. If version control ignore files were
+/// loaded, paths they ignore never trigger. If extensions were given the
+/// path must also have one of them.
+pub struct PathFilter {
+    roots: Vec<PathBuf>,
+    plain_roots: Vec<PathBuf>,
+    target_globs: Vec<TargetGlob>,
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    extensions: Vec<String>,
+    vcs_ignore: Option<VcsIgnore>,
+}
+
+/// Makes a path absolute relative to the current directory. Interior `.`
+/// components are dropped so paths notify reports like `/cwd/./src` compare
+/// equal to `/cwd/src`.
+pub fn absolute(path: &Path) -> PathBuf {
+    let joined = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        match env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(_) => path.to_path_buf(),
+        }
+    };
+    joined.components().collect()
+}
+
+/// A glob watch target. Paths under its base must match the pattern.
+struct TargetGlob {
+    base: PathBuf,
+    pattern: Pattern,
+}
+
+impl PathFilter {
+    pub fn new<P: AsRef<Path>, S: AsRef<str>>(
+        roots: &[P],
+        include: &[S],
+        exclude: &[S],
+    ) -> Result<PathFilter, CommandError> {
+        let mut filter = PathFilter {
+            roots: Vec::new(),
+            plain_roots: Vec::new(),
+            target_globs: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            extensions: Vec::new(),
+            vcs_ignore: None,
+        };
+        for root in roots {
+            filter.add_root(root.as_ref());
+            filter.plain_roots.push(absolute(root.as_ref()));
+        }
+        for pat in include {
+            filter.include.push(Rule::new(pat.as_ref())?);
+        }
+        for pat in exclude {
+            filter.exclude.push(Rule::new(pat.as_ref())?);
+        }
+        Ok(filter)
+    }
+
+    fn add_root(&mut self, root: &Path) {
+        // Notify may report either the path we gave it or the resolved
+        // path so we keep both around for stripping.
+        if let Ok(canonical) = fs::canonicalize(root) {
+            self.roots.push(canonical);
+        }
+        self.roots.push(absolute(root));
+        self.roots.push(root.to_path_buf());
+        // Longest roots first so nested watch roots win.
+        self.roots
+            .sort_by_key(|p| std::cmp::Reverse(p.as_os_str().len()));
+        self.roots.dedup();
+    }
+
+    /// Adds a glob watch target. Paths under `base` only match if they match
+    /// the pattern or are also under a plain watch root.
+    pub fn add_target_glob(&mut self, base: &Path, pattern: &str) -> Result<(), CommandError> {
+        let pattern = if Path::new(pattern).is_absolute() {
+            pattern.to_string()
+        } else {
+            let cwd = env::current_dir()?;
+            format!(
+                "{}/{}",
+                Pattern::escape(&cwd.to_string_lossy()),
+                pattern.trim_start_matches("./")
+            )
+        };
+        self.add_root(base);
+        self.target_globs.push(TargetGlob {
+            base: absolute(base),
+            pattern: Pattern::new(&pattern)?,
+        });
+        Ok(())
+    }
+
+    fn matches_target_globs(&self, path: &Path) -> bool {
+        let abs = absolute(path);
+        let mut globs = self
+            .target_globs
+            .iter()
+            .filter(|g| abs.starts_with(&g.base))
+            .peekable();
+        if globs.peek().is_none() || self.plain_roots.iter().any(|r| abs.starts_with(r)) {
+            return true;
+        }
+        globs.any(|g| g.pattern.matches_path_with(&abs, MATCH_OPTIONS))
+    }
+
+    /// Restricts matches to paths with one of these extensions. A leading
+    /// `.` on an extension is ignored.
+    pub fn set_extensions<S: AsRef<str>>(&mut self, extensions: &[S]) {
+        self.extensions = extensions
+            .iter()
+            .map(|e| e.as_ref().trim_start_matches('.').to_string())
+            .filter(|e| !e.is_empty())
+            .collect();
+    }
+
+    /// Loads `.gitignore`, `.ignore` and global git ignore rules for the
+    /// given watch roots.
+    pub fn load_vcs_ignores<P: AsRef<Path>>(&mut self, roots: &[P]) -> Result<(), CommandError> {
+        let roots: Vec<PathBuf> = roots.iter().map(|r| absolute(r.as_ref())).collect();
+        self.vcs_ignore = Some(VcsIgnore::load(&roots)?);
+        Ok(())
+    }
+
+    /// Reloads the ignore rules if the path is one of the ignore files.
+    pub fn reload_if_ignore_file(&mut self, path: &Path) -> Result<(), CommandError> {
+        if let Some(ref mut ignore) = self.vcs_ignore {
+            if ignore.is_ignore_file(&absolute(path)) {
+                println!("Reloading ignore rules from {:?}", path);
+                ignore.reload()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path relative to the watch root it lives under. A watched
+    /// file is relative to its own parent directory.
+    pub fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        for root in self.roots.iter() {
+            if let Ok(rel) = path.strip_prefix(root) {
+                if !rel.as_os_str().is_empty() {
+                    return rel;
+                }
+            }
+        }
+        match path.file_name() {
+            Some(name) => Path::new(name),
+            None => path,
+        }
+    }
+
+    fn has_extension(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => self.extensions.iter().any(|e| e == ext),
+            None => false,
+        }
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.matches_rules(path) && !self.is_vcs_ignored(path)
+    }
+
+    /// Like `is_match` but ignores the version control ignore files.
+    pub fn matches_rules(&self, path: &Path) -> bool {
+        if !self.has_extension(path) || !self.matches_target_globs(path) {
+            return false;
+        }
+        let rel = self.relative(path);
+        if self.exclude.iter().any(|r| r.matches(path, rel)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|r| r.matches(path, rel))
+    }
+
+    /// Returns true if the loaded version control ignore files ignore the
+    /// path.
+    pub fn is_vcs_ignored(&self, path: &Path) -> bool {
+        match self.vcs_ignore {
+            Some(ref ignore) => ignore.is_ignored(&absolute(path)),
+            None => false,
+        }
+    }
+}
+
+/// A glob rule matched against paths relative to the current directory.
+/// It follows the same rules as `--filter` patterns.
+pub struct PathPattern {
+    rule: Rule,
+    cwd: PathBuf,
+}
+
+impl PathPattern {
+    pub fn new(pat: &str) -> Result<PathPattern, CommandError> {
+        Ok(PathPattern {
+            rule: Rule::new(pat)?,
+            cwd: absolute(Path::new(".")),
+        })
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        let full = absolute(path);
+        let rel = full.strip_prefix(&self.cwd).unwrap_or(&full);
+        self.rule.matches(&full, rel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(roots: &[&str], include: &[&str], exclude: &[&str]) -> PathFilter {
+        PathFilter::new(roots, include, exclude).unwrap()
+    }
+
+    fn rule_matches(pat: &str, rel: &str) -> bool {
+        let rel = Path::new(rel);
+        Rule::new(pat).unwrap().matches(&absolute(rel), rel)
+    }
+
+    #[test]
+    fn unanchored_rules_match_at_any_depth() {
+        assert!(rule_matches("*.rs", "a.rs"));
+        assert!(rule_matches("*.rs", "src/deep/nested/b.rs"));
+        assert!(!rule_matches("*.rs", "src/b.rsx"));
+        assert!(rule_matches("target", "target/debug/runwhen"));
+        assert!(rule_matches("target", "sub/crate/target/debug/runwhen"));
+        assert!(!rule_matches("target", "src/targets.rs"));
+    }
+
+    #[test]
+    fn anchored_rules_only_match_from_the_root() {
+        assert!(rule_matches("src/*.rs", "src/a.rs"));
+        assert!(rule_matches("./src/*.rs", "src/a.rs"));
+        assert!(!rule_matches("src/*.rs", "src/a/b.rs"));
+        assert!(!rule_matches("src/*.rs", "lib/src/a.rs"));
+        // A rule matching a directory matches everything below it.
+        assert!(rule_matches("src/gen/", "src/gen/a/b.rs"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(rule_matches("src/**/*.rs", "src/a.rs"));
+        assert!(rule_matches("src/**/*.rs", "src/a/b/c.rs"));
+        assert!(!rule_matches("src/**/*.rs", "lib/a/b.rs"));
+        assert!(rule_matches("**/fixtures", "tests/data/fixtures/x.json"));
+    }
+
+    #[test]
+    fn include_and_exclude_together() {
+        let f = filter(&["proj"], &["*.rs"], &["generated", "vendor/**"]);
+        assert!(f.matches_rules(Path::new("proj/src/main.rs")));
+        assert!(f.matches_rules(Path::new("proj/src/a/b/c.rs")));
+        assert!(!f.matches_rules(Path::new("proj/README.md")));
+        assert!(!f.matches_rules(Path::new("proj/src/generated/out.rs")));
+        assert!(!f.matches_rules(Path::new("proj/vendor/dep/lib.rs")));
+    }
+
+    #[test]
+    fn exclude_without_include_matches_everything_else() {
+        let f = filter(&["proj"], &[], &["*.swp"]);
+        assert!(f.matches_rules(Path::new("proj/notes.txt")));
+        assert!(!f.matches_rules(Path::new("proj/src/.main.rs.swp")));
+    }
+
+    #[test]
+    fn relative_strips_the_nearest_root() {
+        let f = filter(&["proj", "proj/sub"], &[], &[]);
+        assert_eq!(
+            f.relative(Path::new("proj/sub/a/b.rs")),
+            Path::new("a/b.rs")
+        );
+        assert_eq!(f.relative(Path::new("proj/x/y.rs")), Path::new("x/y.rs"));
+        let abs = absolute(Path::new("proj/sub/c.rs"));
+        assert_eq!(f.relative(&abs), Path::new("c.rs"));
+        // Paths outside every root are matched by their file name.
+        assert_eq!(f.relative(Path::new("/elsewhere/z.rs")), Path::new("z.rs"));
+    }
+
+    #[test]
+    fn anchored_rules_apply_below_nested_roots() {
+        let f = filter(&["proj", "proj/sub"], &["a/*.rs"], &[]);
+        assert!(f.matches_rules(Path::new("proj/sub/a/b.rs")));
+        assert!(f.matches_rules(Path::new("proj/a/b.rs")));
+        assert!(!f.matches_rules(Path::new("proj/sub/b/b.rs")));
+    }
+
+    #[test]
+    fn extensions_ignore_a_leading_dot() {
+        let mut f = filter(&["proj"], &[], &[]);
+        f.set_extensions(&[".rs", "toml"]);
+        assert!(f.matches_rules(Path::new("proj/src/lib.rs")));
+        assert!(f.matches_rules(Path::new("proj/Cargo.toml")));
+        assert!(!f.matches_rules(Path::new("proj/README.md")));
+    }
+}
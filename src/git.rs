@@ -0,0 +1,269 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use error::CommandError;
+use filter::absolute;
+
+/// How long changes are batched in git mode so a branch switch or rebase
+/// touching many files runs the command once.
+pub const GIT_BATCH_WINDOW: Duration = Duration::from_millis(500);
+
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>, CommandError> {
+    let out = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !out.status.success() {
+        return Err(CommandError::new(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out.stdout)
+}
+
+/// Returns the paths among `paths` that git ignores.
+fn check_ignore(dir: &Path, paths: &[PathBuf]) -> Result<HashSet<PathBuf>, CommandError> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["check-ignore", "--stdin", "-z"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        for path in paths.iter() {
+            stdin.write_all(path.to_string_lossy().as_bytes())?;
+            stdin.write_all(b"\0")?;
+        }
+    }
+    let out = child.wait_with_output()?;
+    // NOTE: git exits with 1 when none of the paths are ignored.
+    if !out.status.success() && out.status.code() != Some(1) {
+        return Err(CommandError::new(format!(
+            "git check-ignore failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(out
+        .stdout
+        .split(|b| *b == 0)
+        .filter(|f| !f.is_empty())
+        .map(|f| PathBuf::from(&*String::from_utf8_lossy(f)))
+        .collect())
+}
+
+/// Returns the root of the git repository containing the current directory.
+pub fn repo_root() -> Result<String, CommandError> {
+    let out = git(Path::new("."), &["rev-parse", "--show-toplevel"])?;
+    Ok(String::from_utf8_lossy(&out).trim().to_string())
+}
+
+/// The files git tracks in a repository along with the untracked files it
+/// doesn't ignore.
+pub struct GitFiles {
+    root: PathBuf,
+    git_dir: PathBuf,
+    files: HashSet<PathBuf>,
+    // Paths git ignores. They are only asked about again once the index or
+    // an ignore file changes.
+    unknown: HashSet<PathBuf>,
+    index_modified: Option<SystemTime>,
+}
+
+impl GitFiles {
+    pub fn load(root: &str) -> Result<GitFiles, CommandError> {
+        let root = absolute(Path::new(root));
+        // In worktrees and submodules .git is a file pointing elsewhere so
+        // we let git tell us where its directory is.
+        let git_dir = git(&root, &["rev-parse", "--absolute-git-dir"])?;
+        let mut files = GitFiles {
+            git_dir: PathBuf::from(String::from_utf8_lossy(&git_dir).trim()),
+            root,
+            files: HashSet::new(),
+            unknown: HashSet::new(),
+            index_modified: None,
+        };
+        files.refresh()?;
+        Ok(files)
+    }
+
+    /// Returns the path git holds while it updates the index and the work
+    /// tree.
+    pub fn index_lock(&self) -> PathBuf {
+        self.git_dir.join("index.lock")
+    }
+
+    fn index_modified(&self) -> Option<SystemTime> {
+        fs::metadata(self.git_dir.join("index"))
+            .and_then(|md| md.modified())
+            .ok()
+    }
+
+    fn refresh(&mut self) -> Result<(), CommandError> {
+        self.index_modified = self.index_modified();
+        // NOTE: Deleted files stay in the index until the deletion is
+        // staged so removing a tracked file still counts.
+        let out = git(
+            &self.root,
+            &[
+                "ls-files",
+                "--cached",
+                "--others",
+                "--exclude-standard",
+                "-z",
+            ],
+        )?;
+        self.files = out
+            .split(|b| *b == 0)
+            .filter(|f| !f.is_empty())
+            .map(|f| self.root.join(&*String::from_utf8_lossy(f)))
+            .collect();
+        self.unknown.clear();
+        Ok(())
+    }
+
+    /// Refreshes the file list if a commit, checkout or new ignore rule may
+    /// have changed what git knows about.
+    fn refresh_if_stale(&mut self, path: &Path) {
+        let ignore_file = path.ends_with(".gitignore") || path.ends_with("info/exclude");
+        if ignore_file || self.index_modified() != self.index_modified {
+            self.refresh_or_log();
+        }
+    }
+
+    /// Returns true if the path needs asking git about. `ignored` says
+    /// whether our own reading of the ignore files ignores it so we don't
+    /// ask git about every build artifact.
+    fn is_new(&self, path: &Path, ignored: bool) -> bool {
+        !ignored
+            && !self.files.contains(path)
+            && !self.unknown.contains(path)
+            && !path.starts_with(&self.git_dir)
+            && path.is_file()
+    }
+
+    /// Asks git in a single call about every path it hasn't seen yet, so a
+    /// burst of new files doesn't run git once for each.
+    pub fn look_up<F>(&mut self, paths: &[&Path], ignored: F)
+    where
+        F: Fn(&Path) -> bool,
+    {
+        for path in paths.iter() {
+            self.refresh_if_stale(&absolute(path));
+        }
+        let new: Vec<PathBuf> = paths
+            .iter()
+            .filter(|p| self.is_new(&absolute(p), ignored(p)))
+            .map(|p| absolute(p))
+            .collect();
+        if new.is_empty() {
+            return;
+        }
+        match check_ignore(&self.root, &new) {
+            Ok(ignored) => {
+                for path in new {
+                    if ignored.contains(&path) {
+                        self.unknown.insert(path);
+                    } else {
+                        self.files.insert(path);
+                    }
+                }
+            }
+            Err(err) => {
+                println!("Failed to ask git which new files it ignores");
+                println!("{:?}", err);
+            }
+        }
+    }
+
+    /// Returns true if git tracks the path or it is untracked but not
+    /// ignored. A directory counts if git tracks a file under it, so moving
+    /// or removing a tracked directory is noticed.
+    pub fn is_relevant(&mut self, path: &Path, ignored: bool) -> bool {
+        let path = absolute(path);
+        self.look_up(&[&path], |_| ignored);
+        if self.files.contains(&path) {
+            return true;
+        }
+        if ignored || path.is_file() || path.starts_with(&self.git_dir) {
+            return false;
+        }
+        // Either a directory or something that no longer exists.
+        self.files.iter().any(|f| f.starts_with(&path))
+    }
+
+    fn refresh_or_log(&mut self) {
+        if let Err(err) = self.refresh() {
+            println!("Failed to list the files git knows about");
+            println!("{:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn scratch_repo(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("runwhen-git-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "-q"]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn ignored_files_are_not_asked_about_again_until_an_ignore_file_changes() {
+        let dir = scratch_repo("ignored");
+        fs::write(dir.join(".gitignore"), "*.o\n").unwrap();
+        let mut files = GitFiles::load(dir.to_str().unwrap()).unwrap();
+        let (obj, src) = (dir.join("a.o"), dir.join("a.c"));
+        fs::write(&obj, "").unwrap();
+        fs::write(&src, "").unwrap();
+        files.look_up(&[&obj, &src], |_| false);
+        assert!(!files.is_relevant(&obj, false));
+        assert!(files.is_relevant(&src, false));
+
+        // Until we see the ignore file change the answer is remembered.
+        fs::write(dir.join(".gitignore"), "").unwrap();
+        assert!(!files.is_relevant(&obj, false));
+        assert!(files.is_relevant(&dir.join(".gitignore"), false));
+        assert!(files.is_relevant(&obj, false));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_directory_counts_if_it_holds_tracked_files() {
+        let dir = scratch_repo("dirs");
+        fs::create_dir_all(dir.join("src/a")).unwrap();
+        fs::create_dir_all(dir.join("src/empty")).unwrap();
+        fs::write(dir.join("src/a/x.rs"), "").unwrap();
+        git(&dir, &["add", "src/a/x.rs"]).unwrap();
+        let mut files = GitFiles::load(dir.to_str().unwrap()).unwrap();
+
+        fs::rename(dir.join("src/a"), dir.join("src/b")).unwrap();
+        assert!(files.is_relevant(&dir.join("src/a"), false));
+        assert!(!files.is_relevant(&dir.join("src/empty"), false));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
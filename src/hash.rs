@@ -0,0 +1,317 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use error::CommandError;
+use filter::absolute;
+use watches::WatchDepth;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Hashes a file's contents with 64 bit FNV-1a. We only need to notice
+/// changes and the hash has to be stable across runs for the cache file.
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = [0; 8192];
+    let mut hash = FNV_OFFSET;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for b in buf[..n].iter() {
+            hash ^= u64::from(*b);
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Remembers the size and content hash of files we have seen change so
+/// rewrites of identical content can be ignored.
+pub struct ContentCache {
+    entries: HashMap<PathBuf, (u64, u64)>,
+    max_size: u64,
+    cache_file: Option<PathBuf>,
+    // Set when entries changed since the cache file was last written.
+    dirty: bool,
+}
+
+impl ContentCache {
+    /// Creates a cache that hashes files up to `max_size` bytes. If a cache
+    /// file is given, previously seen hashes are loaded from it and it is
+    /// updated as files change.
+    pub fn new(max_size: u64, cache_file: Option<&str>) -> Result<ContentCache, CommandError> {
+        let mut cache = ContentCache {
+            entries: HashMap::new(),
+            max_size,
+            cache_file: cache_file.map(|f| absolute(Path::new(f))),
+            dirty: false,
+        };
+        if let Some(ref path) = cache.cache_file {
+            if path.exists() {
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let line = line?;
+                    let mut parts = line.splitn(3, ' ');
+                    let hash = parts.next().map(|h| u64::from_str_radix(h, 16));
+                    let size = parts.next().map(|s| s.parse::<u64>());
+                    if let (Some(Ok(hash)), Some(Ok(size)), Some(file)) = (hash, size, parts.next())
+                    {
+                        cache.entries.insert(PathBuf::from(file), (hash, size));
+                    }
+                }
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Returns the file the cache is written to before it is renamed into
+    /// place.
+    fn tmp_file(path: &Path) -> PathBuf {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        PathBuf::from(tmp)
+    }
+
+    /// Returns true if the path is the cache file or its temporary file.
+    /// Writing them must not count as a change or every save would fire.
+    fn is_own_file(&self, path: &Path) -> bool {
+        match self.cache_file {
+            Some(ref file) => {
+                let path = absolute(path);
+                path == *file || path == Self::tmp_file(file)
+            }
+            None => false,
+        }
+    }
+
+    fn save(&mut self) -> Result<(), CommandError> {
+        let path = match self.cache_file {
+            Some(ref path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+        // Write then rename so a crash can't leave a truncated cache.
+        let tmp = Self::tmp_file(path);
+        let mut out = File::create(&tmp)?;
+        for (file, (hash, size)) in self.entries.iter() {
+            writeln!(out, "{:016x} {} {}", hash, size, file.to_string_lossy())?;
+        }
+        out.sync_all()?;
+        fs::rename(&tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Hashes the files in the watch targets so the first rewrite of
+    /// identical content after startup doesn't fire. Only paths `wanted`
+    /// accepts are hashed or descended into. Files already loaded from the
+    /// cache file keep their saved hash so a change made while we weren't
+    /// running still fires.
+    pub fn record_targets<F>(&mut self, targets: &[(PathBuf, WatchDepth)], wanted: &F)
+    where
+        F: Fn(&Path) -> bool,
+    {
+        for (target, depth) in targets.iter() {
+            if target.is_dir() {
+                self.record_dir(target, 1, *depth, wanted);
+            } else {
+                self.record(target);
+            }
+        }
+        self.save_or_log();
+    }
+
+    fn record_dir<F>(&mut self, dir: &Path, levels: usize, depth: WatchDepth, wanted: &F)
+    where
+        F: Fn(&Path) -> bool,
+    {
+        if !depth.allows(levels) {
+            return;
+        }
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !wanted(&path) {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(ft) if ft.is_dir() => self.record_dir(&path, levels + 1, depth, wanted),
+                Ok(ft) if ft.is_file() => self.record(&path),
+                _ => (),
+            }
+        }
+    }
+
+    fn record(&mut self, path: &Path) {
+        let key = absolute(path);
+        if self.entries.contains_key(&key) || self.is_own_file(&key) {
+            return;
+        }
+        let size = match fs::metadata(&key) {
+            Ok(md) if md.is_file() && md.len() <= self.max_size => md.len(),
+            _ => return,
+        };
+        if let Ok(hash) = hash_file(&key) {
+            self.entries.insert(key, (hash, size));
+            self.dirty = true;
+        }
+    }
+
+    /// Drops the paths whose content hasn't changed since we last saw them
+    /// along with the cache's own files, then saves the cache once for the
+    /// whole batch.
+    pub fn retain_changed(&mut self, paths: &mut Vec<PathBuf>) {
+        paths.retain(|p| !self.is_own_file(p) && self.changed(p));
+        self.save_or_log();
+    }
+
+    /// Returns true if the file's content differs from the last time we saw
+    /// it. Missing files, directories, files we can't read and files over
+    /// the size limit always count as changed.
+    fn changed(&mut self, path: &Path) -> bool {
+        let key = absolute(path);
+        let size = match fs::metadata(&key) {
+            Ok(md) if md.is_file() => md.len(),
+            Ok(_) => return true,
+            Err(_) => {
+                if self.entries.remove(&key).is_some() {
+                    self.dirty = true;
+                }
+                return true;
+            }
+        };
+        if size > self.max_size {
+            return true;
+        }
+        let hash = match hash_file(&key) {
+            Ok(hash) => hash,
+            Err(_) => return true,
+        };
+        if self.entries.get(&key) == Some(&(hash, size)) {
+            println!("Content of {:?} is unchanged", path);
+            return false;
+        }
+        self.entries.insert(key, (hash, size));
+        self.dirty = true;
+        true
+    }
+
+    fn save_or_log(&mut self) {
+        if let Err(err) = self.save() {
+            println!("Failed to save content hash cache");
+            println!("{:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("runwhen-hash-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn everything(_: &Path) -> bool {
+        true
+    }
+
+    #[test]
+    fn writes_to_the_cache_file_are_not_changes() {
+        let dir = scratch_dir("own-file");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let cache_file = dir.join("hashes");
+        let mut cache = ContentCache::new(1024, cache_file.to_str()).unwrap();
+        cache.record_targets(&[(dir.clone(), WatchDepth::Recursive)], &everything);
+
+        fs::write(&file, "b").unwrap();
+        let mut paths = vec![file.clone()];
+        cache.retain_changed(&mut paths);
+        assert_eq!(paths, vec![file.clone()]);
+
+        // Saving the cache above wrote both of these.
+        let mut paths = vec![cache_file.clone(), ContentCache::tmp_file(&cache_file)];
+        cache.retain_changed(&mut paths);
+        assert!(paths.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rewriting_the_same_content_is_not_a_change() {
+        let dir = scratch_dir("same-content");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let mut cache = ContentCache::new(1024, None).unwrap();
+        cache.record_targets(&[(dir.clone(), WatchDepth::Recursive)], &everything);
+
+        fs::write(&file, "a").unwrap();
+        let mut paths = vec![file.clone()];
+        cache.retain_changed(&mut paths);
+        assert!(paths.is_empty());
+
+        fs::write(&file, "b").unwrap();
+        let mut paths = vec![file.clone(), file.clone()];
+        cache.retain_changed(&mut paths);
+        assert_eq!(paths, vec![file.clone()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_change_made_while_stopped_fires_on_the_next_write() {
+        let dir = scratch_dir("persisted");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let cache_file = dir.join("hashes");
+        {
+            let mut cache = ContentCache::new(1024, cache_file.to_str()).unwrap();
+            cache.record_targets(&[(dir.clone(), WatchDepth::Recursive)], &everything);
+        }
+
+        fs::write(&file, "b").unwrap();
+        let mut cache = ContentCache::new(1024, cache_file.to_str()).unwrap();
+        cache.record_targets(&[(dir.clone(), WatchDepth::Recursive)], &everything);
+        let mut paths = vec![file.clone()];
+        cache.retain_changed(&mut paths);
+        assert_eq!(paths, vec![file.clone()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_cache_file_named_tmp_is_saved_beside_itself() {
+        let dir = scratch_dir("tmp-name");
+        let file = dir.join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let cache_file = dir.join("hashes.tmp");
+        let mut cache = ContentCache::new(1024, cache_file.to_str()).unwrap();
+        cache.record_targets(&[(file.clone(), WatchDepth::Recursive)], &everything);
+
+        assert!(cache_file.is_file());
+        assert!(!dir.join("hashes.tmp.tmp").exists());
+        let loaded = ContentCache::new(1024, cache_file.to_str()).unwrap();
+        assert!(loaded.entries.contains_key(&absolute(&file)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
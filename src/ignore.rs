@@ -0,0 +1,247 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+
+use error::CommandError;
+use filter::MATCH_OPTIONS;
+
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// A single line from an ignore file.
+struct IgnoreRule {
+    pattern: Pattern,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<IgnoreRule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        // NOTE: We skip patterns glob can't parse rather than refusing
+        // to watch a tree because of somebody else's ignore file.
+        let pattern = Pattern::new(line).ok()?;
+        Some(IgnoreRule {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            return self.pattern.matches_path_with(rel, MATCH_OPTIONS);
+        }
+        match rel.file_name().and_then(|n| n.to_str()) {
+            Some(name) => self.pattern.matches_with(name, MATCH_OPTIONS),
+            None => false,
+        }
+    }
+}
+
+/// The rules from one ignore file along with the directory they apply to.
+struct IgnoreFile {
+    path: PathBuf,
+    base: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    fn load(path: PathBuf, base: PathBuf) -> Option<IgnoreFile> {
+        let contents = fs::read_to_string(&path).ok()?;
+        let rules = contents.lines().filter_map(IgnoreRule::parse).collect();
+        Some(IgnoreFile { path, base, rules })
+    }
+
+    /// Returns Some(true) if the last matching rule ignores the path,
+    /// Some(false) if it was re-included and None if nothing matched.
+    fn decide(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let rel = match path.strip_prefix(&self.base) {
+            Ok(rel) if !rel.as_os_str().is_empty() => rel,
+            _ => return None,
+        };
+        self.rules
+            .iter()
+            .rev()
+            .find(|r| r.matches(rel, is_dir))
+            .map(|r| !r.negated)
+    }
+}
+
+fn global_ignore_file() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("git").join("ignore")),
+        _ => {
+            env::var_os("HOME").map(|h| PathBuf::from(h).join(".config").join("git").join("ignore"))
+        }
+    }
+}
+
+fn repo_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find(|d| d.join(".git").exists())
+}
+
+/// Ignore rules loaded from `.gitignore` and `.ignore` files in and above the
+/// watched directories, the repository's `.git/info/exclude` and the user's
+/// global git ignore file.
+pub struct VcsIgnore {
+    roots: Vec<PathBuf>,
+    // Ordered from lowest to highest precedence.
+    files: Vec<IgnoreFile>,
+}
+
+impl VcsIgnore {
+    pub fn load(roots: &[PathBuf]) -> Result<VcsIgnore, CommandError> {
+        let mut ignore = VcsIgnore {
+            roots: roots.to_vec(),
+            files: Vec::new(),
+        };
+        ignore.reload()?;
+        Ok(ignore)
+    }
+
+    pub fn reload(&mut self) -> Result<(), CommandError> {
+        self.files.clear();
+        for root in self.roots.clone() {
+            let dir = if root.is_dir() {
+                root.clone()
+            } else {
+                match root.parent() {
+                    Some(p) => p.to_path_buf(),
+                    None => continue,
+                }
+            };
+            let top = repo_root(&dir).unwrap_or(&dir).to_path_buf();
+            if let Some(global) = global_ignore_file() {
+                self.push_file(global, top.clone());
+            }
+            self.push_file(top.join(".git").join("info").join("exclude"), top.clone());
+            // Ignore files between the repository root and the watch root
+            // apply too.
+            let mut parents: Vec<&Path> = dir
+                .ancestors()
+                .skip(1)
+                .take_while(|p| p.starts_with(&top))
+                .collect();
+            parents.reverse();
+            for parent in parents {
+                self.push_dir(parent);
+            }
+            if root.is_dir() {
+                self.walk(&dir)?;
+            } else {
+                self.push_dir(&dir);
+            }
+        }
+        Ok(())
+    }
+
+    fn push_file(&mut self, path: PathBuf, base: PathBuf) {
+        if self.files.iter().any(|f| f.path == path && f.base == base) {
+            return;
+        }
+        if let Some(file) = IgnoreFile::load(path, base) {
+            self.files.push(file);
+        }
+    }
+
+    fn push_dir(&mut self, dir: &Path) {
+        for name in IGNORE_FILES.iter() {
+            self.push_file(dir.join(name), dir.to_path_buf());
+        }
+    }
+
+    fn walk(&mut self, dir: &Path) -> Result<(), CommandError> {
+        self.push_dir(dir);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            // Directories we can't read can't be watched either.
+            Err(_) => return Ok(()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || entry.file_name() == ".git" {
+                continue;
+            }
+            let path = entry.path();
+            if !self.is_ignored(&path) {
+                self.walk(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decide(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for file in self.files.iter() {
+            if let Some(decision) = file.decide(path, is_dir) {
+                ignored = decision;
+            }
+        }
+        ignored
+    }
+
+    /// Returns true if the path or any of its parent directories below a
+    /// watch root is ignored or is a `.git` directory. The path must be
+    /// absolute.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let mut prefixes: Vec<&Path> = path
+            .ancestors()
+            .take_while(|p| self.roots.iter().any(|r| p.starts_with(r) && p != r))
+            .collect();
+        if prefixes.is_empty() {
+            return false;
+        }
+        // Like git itself we never look inside .git directories.
+        if prefixes.iter().any(|p| p.ends_with(".git")) {
+            return true;
+        }
+        prefixes.reverse();
+        let last = prefixes.len() - 1;
+        prefixes
+            .iter()
+            .enumerate()
+            .any(|(i, p)| self.decide(p, i != last || p.is_dir()))
+    }
+
+    /// Returns true if a change to this path means the rules must be
+    /// reloaded.
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if IGNORE_FILES.contains(&name) => true,
+            _ => path.ends_with(".git/info/exclude") || self.files.iter().any(|f| f.path == path),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use error::CommandError;
+use filter::absolute;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format, CommandError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Ok(Format::Json),
+            Some("toml") => Ok(Format::Toml),
+            Some("yaml") | Some("yml") => Ok(Format::Yaml),
+            _ => Err(CommandError::new(format!(
+                "Can't tell the format of {:?}: expected a .json, .toml, .yaml or .yml file",
+                path
+            ))),
+        }
+    }
+
+    fn parse(&self, contents: &str) -> Result<Value, CommandError> {
+        // NOTE: Everything is parsed into json values so one selector
+        // implementation covers all three formats.
+        match self {
+            Format::Json => {
+                serde_json::from_str(contents).map_err(|e| CommandError::new(e.to_string()))
+            }
+            Format::Toml => toml::from_str(contents).map_err(|e| CommandError::new(e.to_string())),
+            Format::Yaml => {
+                serde_yaml::from_str(contents).map_err(|e| CommandError::new(e.to_string()))
+            }
+        }
+    }
+}
+
+/// Looks a value up by JSON pointer if the selector starts with `/` or
+/// by dotted path like `database.url` otherwise. Numeric segments of a
+/// dotted path index into arrays.
+fn select<'v>(value: &'v Value, selector: &str) -> Option<&'v Value> {
+    if selector.starts_with('/') {
+        return value.pointer(selector);
+    }
+    selector.split('.').try_fold(value, |v, key| match v {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+/// A structured config file and the keys in it we care about.
+struct KeyedFile {
+    path: PathBuf,
+    format: Format,
+    selectors: Vec<String>,
+    // The selected values from the last successful parse.
+    values: Vec<Option<Value>>,
+}
+
+impl KeyedFile {
+    fn read(&self) -> Result<Vec<Option<Value>>, CommandError> {
+        let doc = self.format.parse(&fs::read_to_string(&self.path)?)?;
+        Ok(self
+            .selectors
+            .iter()
+            .map(|s| select(&doc, s).cloned())
+            .collect())
+    }
+}
+
+/// Lets changes to structured config files through only when one of the
+/// selected keys has a different value, so edits to comments, formatting or
+/// unrelated keys don't fire.
+pub struct KeyFilter {
+    files: Vec<KeyedFile>,
+}
+
+impl KeyFilter {
+    /// Creates a filter from specs like `config.toml#database.url` or
+    /// `package.json#/version`.
+    pub fn new<S: AsRef<str>>(specs: &[S]) -> Result<KeyFilter, CommandError> {
+        let mut filter = KeyFilter { files: Vec::new() };
+        for spec in specs {
+            let spec = spec.as_ref();
+            let (file, selector) = match spec.find('#') {
+                Some(idx) if idx > 0 && idx + 1 < spec.len() => (&spec[..idx], &spec[idx + 1..]),
+                _ => {
+                    return Err(CommandError::new(format!(
+                        "Invalid config key {}: expected file#key",
+                        spec
+                    )))
+                }
+            };
+            let path = absolute(Path::new(file));
+            let format = Format::of(&path)?;
+            match filter.files.iter_mut().find(|f| f.path == path) {
+                Some(keyed) => keyed.selectors.push(selector.to_string()),
+                None => filter.files.push(KeyedFile {
+                    path,
+                    format,
+                    selectors: vec![selector.to_string()],
+                    values: Vec::new(),
+                }),
+            }
+        }
+        for keyed in filter.files.iter_mut() {
+            // A file that is missing or broken at startup has no values yet.
+            keyed.values = keyed
+                .read()
+                .unwrap_or_else(|_| vec![None; keyed.selectors.len()]);
+        }
+        Ok(filter)
+    }
+
+    /// Returns false if the path is one of the config files and none of its
+    /// selected keys changed. Other paths always count as changed.
+    pub fn changed(&mut self, path: &Path) -> bool {
+        let path = absolute(path);
+        let keyed = match self.files.iter_mut().find(|f| f.path == path) {
+            Some(keyed) => keyed,
+            None => return true,
+        };
+        let values = if keyed.path.exists() {
+            match keyed.read() {
+                Ok(values) => values,
+                Err(err) => {
+                    println!("Failed to parse {:?}, ignoring the change", keyed.path);
+                    println!("{:?}", err);
+                    return false;
+                }
+            }
+        } else {
+            vec![None; keyed.selectors.len()]
+        };
+        if values == keyed.values {
+            println!("No watched keys changed in {:?}", keyed.path);
+            return false;
+        }
+        keyed.values = values;
+        true
+    }
+}
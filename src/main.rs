@@ -12,26 +12,50 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 // runwhen - A utility that runs commands on user defined triggers.
+extern crate chrono;
 #[macro_use]
 extern crate clap;
 extern crate glob;
 extern crate humantime;
+#[cfg(unix)]
+extern crate libc;
 extern crate notify;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
 
-use std::{process, str::FromStr};
+use std::{process, str::FromStr, time::Duration};
 
+mod cron;
+mod debounce;
 mod error;
 mod events;
 mod exec;
 mod file;
+mod filter;
+mod git;
+mod hash;
+mod ignore;
+mod keys;
+mod pause;
+mod poll;
+mod presets;
+mod settle;
+mod spool;
+mod stale;
 mod timer;
 mod traits;
+mod watches;
 
-use events::WatchEventType;
+use debounce::Edge;
+use events::{EventKind, WatchEventType};
 use exec::ExecProcess;
-use file::FileProcess;
-use timer::TimerProcess;
+use file::{Backend, BusyPolicy, FileProcess, Route, WatchOptions};
+use spool::SpoolProcess;
+use stale::StaleProcess;
+use timer::{Interval, IntervalMode, Schedule, TimerProcess};
 use traits::Process;
+use watches::WatchDepth;
 
 #[rustfmt::skip]
 fn do_flags() -> clap::ArgMatches {
@@ -46,19 +70,88 @@ fn do_flags() -> clap::ArgMatches {
                 .about("Trigger that fires when a file or directory changes.")
                 .arg(
                     arg!(-f --file ...).name("file")
-                        .takes_value(true).help("File or directory to watch for changes"),
+                        .takes_value(true).help("File or directory to watch for changes. Append :recursive, :non-recursive or :depth=N to set how deep to watch it"),
                 )
                 .arg(
                     arg!(-e --exclude ...).name("exclude")
                         .takes_value(true).help("path names to skip when watching. Specified in unix glob format."),
                 )
+                .arg(
+                    arg!(--filter ...).name("filter")
+                        .takes_value(true).help("Only trigger for path names matching this pattern. Specified in unix glob format."),
+                )
+                .arg(
+                    arg!(--ext ...).name("ext")
+                        .takes_value(true).use_value_delimiter(true)
+                        .help("Only trigger for files with these comma separated extensions"),
+                )
+                .arg(
+                    arg!(--on ...).name("on")
+                        .takes_value(true).use_value_delimiter(true)
+                        .value_parser(value_parser!(EventKind))
+                        .help("Only trigger on these comma separated event kinds: create, write, remove, rename, chmod"),
+                )
+                .arg(
+                    arg!(--preset ...).name("preset")
+                        .takes_value(true).use_value_delimiter(true)
+                        .help("Add the filter and exclude rules for these comma separated presets: rust, node, python, go, editors. See runwhen presets --list"),
+                )
+                .arg(arg!(--git).name("git").help("Only trigger for files git tracks or doesn't ignore. Watches the repository root by default and batches branch switches into one run"))
+                .arg(arg!(--"no-vcs-ignore").name("novcsignore").help("Don't skip paths ignored by .gitignore, .ignore or the global git ignore file."))
                 .arg(arg!(--touch).name("filetouch").help("Use file or directory timestamps to monitor for changes."))
-            .arg(arg!(--poll).name("poll").takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Duration of time between polls")))
+                .arg(arg!(--"config-key" ...).name("configkey").takes_value(true).help("Only trigger for a config file when this key changes value, given as file#key. Keys are dotted paths like config.toml#database.url or JSON pointers like package.json#/version. Supports json, toml and yaml"))
+                .arg(arg!(--debounce).name("debounce").takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Wait for events to be quiet this long and run once for the whole batch"))
+                .arg(arg!(--"debounce-edge").name("edge").takes_value(true).value_parser(value_parser!(Edge)).help("Which edge of a burst of events runs the command: leading, trailing or both. Defaults to trailing."))
+                .arg(arg!(--"on-busy").name("onbusy").takes_value(true).value_parser(value_parser!(BusyPolicy)).help("What to do when changes arrive while the command is running: restart, queue, ignore or signal. Defaults to restart."))
+                .arg(arg!(--"busy-signal").name("busysignal").takes_value(true).help("The signal to send for --on-busy signal. Defaults to HUP."))
+                .arg(arg!(--backend).name("backend").takes_value(true).value_parser(value_parser!(Backend)).help("How to watch for changes: native or poll. Use poll for NFS, container bind mounts or WSL shares. Defaults to native."))
+                .arg(arg!(--"poll-interval").name("pollinterval").takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Duration between scans for the poll backend and for paths past the inotify watch limit. Defaults to 1s."))
+                .arg(arg!(--"fire-on-rescan").name("fireonrescan").help("Run the command when the watcher loses events and has to rescan"))
+                .arg(arg!(--"content-hash").name("contenthash").help("Only trigger when a file's content has changed, not just its timestamp"))
+                .arg(arg!(--"hash-max-size").name("hashmaxsize").takes_value(true).value_parser(value_parser!(u64)).help("Files larger than this many bytes always count as changed. Defaults to 64MiB"))
+                .arg(arg!(--"hash-cache").name("hashcache").takes_value(true).help("File to keep content hashes in between runs. Implies --content-hash"))
+                .arg(arg!(--settle).name("settle").takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Only trigger once a changed file's size and modification time have been stable this long"))
+                .arg(arg!(--"ignore-partial").name("ignorepartial").help("Skip dotfiles and .part files that transfers write to before the final name"))
+                .arg(arg!(--"pause-while" ...).name("pausewhile").takes_value(true).help("Hold changes while this path exists, like .git/index.lock, and run once when it is gone"))
+                .arg(arg!(--"non-recursive").name("nonrecursive").help("Only watch the direct entries of watched directories"))
+                .arg(arg!(--"max-depth").name("maxdepth").takes_value(true).value_parser(value_parser!(usize)).help("Only watch this many levels below watched directories"))
+                .arg(arg!(--"allow-missing").name("allowmissing").help("Wait for watched paths that don't exist yet and keep watching them if they are deleted and recreated"))
+                .arg(arg!(--"no-initial-run").name("noinitialrun").overrides_with("initialrun").help("Don't run the command until the first change"))
+                .arg(arg!(--"initial-run").name("initialrun").overrides_with("noinitialrun").help("Run the command at startup. This is the default"))
+                .arg(arg!(--"initial-delay").name("initialdelay").takes_value(true).value_parser(value_parser!(humantime::Duration)).conflicts_with("noinitialrun").help("Wait this long before the run at startup"))
+                .arg(arg!(--"ignore-own-changes").name("ignoreown").help("Ignore changes made while the command runs and shortly after so it can write into the watched paths"))
+                .arg(arg!(--"own-changes-grace").name("owngrace").takes_value(true).value_parser(value_parser!(humantime::Duration)).requires("ignoreown").help("How long after the command exits its changes are still ignored. Defaults to 2s"))
+                .arg(arg!(--route ...).name("route").takes_value(true).help("Run a different command for changes to matching paths, given as 'pattern => command'. Changes no route matches run --cmd if it was given"))
+                .arg(arg!(--"files-from").name("filesfrom").takes_value(true).help("Read more paths to watch from this file, one per line or NUL separated. Use - for stdin"))
+                .arg(arg!(--"reload-files-from").name("reloadfilesfrom").requires("filesfrom").help("Re-read the --files-from list when it changes"))
+                .arg(arg!(--"fire-on-create").name("fireoncreate").help("Run the command when a missing watched path is created. Implies --allow-missing"))
+            .arg(arg!(--poll).name("poll").takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Run at most once per this duration. Same as --debounce with --debounce-edge both")))
         .subcommand(
             clap::Command::new("timer")
                 .about("Run command on a timer")
                 .arg(arg!(-t --duration).takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Duration between runs"))
-                .arg(arg!(-n --repeat).value_parser(value_parser!(u32)).help("Number of times to run before finishing")))
+                .arg(arg!(--cron).name("cron").takes_value(true).value_parser(value_parser!(cron::CronSchedule)).conflicts_with("duration").help("Run at times matching this cron expression in local time, e.g. '*/5 9-17 * * 1-5'. Takes an optional leading seconds field and aliases like @daily"))
+                .arg(arg!(--schedule).name("schedule").takes_value(true).value_parser(value_parser!(IntervalMode)).conflicts_with("cron").help("fixed-delay waits the duration after each run finishes. fixed-rate starts runs the duration apart however long they take. Defaults to fixed-delay or fixed-rate with --align"))
+                .arg(arg!(--align).name("align").conflicts_with("cron").help("Start runs on multiples of the duration since the Unix epoch, e.g. -t 5m fires at :00, :05, :10"))
+                .arg(arg!(-n --repeat).takes_value(true).value_parser(value_parser!(u32)).help("Number of times to run before finishing"))
+                .group(clap::ArgGroup::new("timing").args(&["duration", "cron"]).required(true)))
+        .subcommand(
+            clap::Command::new("spool")
+                .about("Run command once for each file added to a directory")
+                .arg(arg!(-d --dir).name("dir").takes_value(true).required(true).help("The inbox directory to take files from"))
+                .arg(arg!(--done).name("done").takes_value(true).help("Where to move files the command succeeded for. Defaults to done in the inbox"))
+                .arg(arg!(--failed).name("failed").takes_value(true).help("Where to move files the command failed for. Defaults to failed in the inbox"))
+                .arg(arg!(--settle).name("settle").takes_value(true).value_parser(value_parser!(humantime::Duration)).help("Only take a file once its size and modification time have been stable this long"))
+                .arg(arg!(--"ignore-partial").name("ignorepartial").help("Skip dotfiles and .part files that transfers write to before the final name")))
+        .subcommand(
+            clap::Command::new("stale")
+                .about("Trigger that fires when a file hasn't changed for too long")
+                .arg(arg!(-f --file ...).name("file").takes_value(true).required(true).help("File that is expected to change regularly"))
+                .arg(arg!(--"max-age").name("maxage").takes_value(true).required(true).value_parser(value_parser!(humantime::Duration)).help("Run the command when a file hasn't been modified for this long")))
+        .subcommand(
+            clap::Command::new("presets")
+                .about("Show the presets available to the watch trigger")
+                .arg(arg!(--list).help("List the presets and their rules. This is the default")))
         .subcommand(
             clap::Command::new("success")
             .about("Run a command when a test command succeeds")
@@ -70,8 +163,15 @@ fn do_flags() -> clap::ArgMatches {
 
 fn main() {
     let app = do_flags();
-    // Unwrap because this flag is required.
-    let cmd = app.value_of("cmd").expect("cmd flag is required");
+    // The watch trigger can run commands from its routes instead.
+    let maybe_cmd = app.value_of("cmd");
+    let cmd = || match maybe_cmd {
+        Some(cmd) => cmd,
+        None => {
+            println!("You must specify a command with --cmd.");
+            process::exit(1)
+        }
+    };
     let mut maybe_env = None;
     if let Some(env_values) = app.values_of("env") {
         let mut env_vec = Vec::new();
@@ -81,37 +181,216 @@ fn main() {
         maybe_env = Some(env_vec);
     }
 
+    let git_root = match app.subcommand_matches("watch") {
+        Some(matches) if matches.is_present("git") => match git::repo_root() {
+            Ok(root) => Some(root),
+            Err(err) => {
+                println!("{0}", err);
+                process::exit(1)
+            }
+        },
+        _ => None,
+    };
+
     let mut proc: Box<dyn Process> = if let Some(matches) = app.subcommand_matches("watch") {
         let file = match matches.values_of("file") {
             Some(v) => v.collect(),
-            // The default is our current directory
-            None => vec!["."],
+            None => match git_root {
+                Some(ref root) => vec![root.as_str()],
+                None if matches.is_present("filesfrom") => Vec::new(),
+                // The default is our current directory
+                None => vec!["."],
+            },
         };
         let mut method = WatchEventType::Changed;
         if matches.is_present("filetouch") {
             method = WatchEventType::Touched;
         }
-        let duration = match matches.get_one::<humantime::Duration>("poll") {
-            Some(d) => Some((*d).into()),
-            None => None,
+        let duration = matches
+            .get_one::<humantime::Duration>("poll")
+            .map(|d| (*d).into());
+        let mut include: Vec<&str> = match matches.values_of("filter") {
+            Some(vr) => vr.collect(),
+            None => Vec::new(),
+        };
+        let mut exclude: Vec<&str> = match matches.values_of("exclude") {
+            Some(vr) => vr.collect(),
+            None => Vec::new(),
+        };
+        if matches.is_present("ignorepartial") {
+            exclude.extend([".*", "*.part"]);
+        }
+        for name in matches.values_of("preset").into_iter().flatten() {
+            match presets::find(name) {
+                Ok(preset) => {
+                    include.extend(preset.include);
+                    exclude.extend(preset.exclude);
+                }
+                Err(err) => {
+                    println!("{0}", err);
+                    process::exit(1)
+                }
+            }
+        }
+        let busy_signal = match matches.value_of("busysignal").map(exec::parse_signal) {
+            Some(Ok(sig)) => sig,
+            Some(Err(err)) => {
+                println!("{0}", err);
+                process::exit(1)
+            }
+            None => exec::DEFAULT_SIGNAL,
         };
-        let exclude = match matches.values_of("exclude") {
-            Some(vr) => Some(vr.collect()),
-            None => None,
+        let depth = match matches.get_one::<usize>("maxdepth") {
+            Some(0) => {
+                println!("--max-depth must be at least 1");
+                process::exit(1)
+            }
+            Some(1) => WatchDepth::NonRecursive,
+            Some(n) => WatchDepth::Limited(*n),
+            None if matches.is_present("nonrecursive") => WatchDepth::NonRecursive,
+            None => WatchDepth::Recursive,
         };
+        let mut routes = Vec::new();
+        for spec in matches.values_of("route").into_iter().flatten() {
+            match Route::parse(spec) {
+                Ok(route) => routes.push(route),
+                Err(err) => {
+                    println!("{0}", err);
+                    process::exit(1)
+                }
+            }
+        }
+        if routes.is_empty() {
+            cmd();
+        }
         println!("Enforcing a poll time of {:?}", duration);
-        Box::new(FileProcess::new(
-            cmd, maybe_env, file, exclude, method, duration,
-        ))
+        let opts = WatchOptions {
+            include,
+            exclude,
+            vcs_ignore: !matches.is_present("novcsignore"),
+            extensions: match matches.values_of("ext") {
+                Some(vr) => vr.collect(),
+                None => Vec::new(),
+            },
+            kinds: matches
+                .get_many::<EventKind>("on")
+                .map(|kinds| kinds.cloned().collect()),
+            method,
+            content_hash: matches.is_present("contenthash") || matches.is_present("hashcache"),
+            hash_max_size: matches
+                .get_one::<u64>("hashmaxsize")
+                .cloned()
+                .unwrap_or(64 * 1024 * 1024),
+            hash_cache: matches.value_of("hashcache"),
+            config_keys: match matches.values_of("configkey") {
+                Some(vr) => vr.collect(),
+                None => Vec::new(),
+            },
+            debounce: matches
+                .get_one::<humantime::Duration>("debounce")
+                .map(|d| (*d).into()),
+            edge: matches
+                .get_one::<Edge>("edge")
+                .cloned()
+                .unwrap_or(Edge::Trailing),
+            on_busy: matches
+                .get_one::<BusyPolicy>("onbusy")
+                .cloned()
+                .unwrap_or(BusyPolicy::Restart),
+            busy_signal,
+            depth,
+            backend: matches
+                .get_one::<Backend>("backend")
+                .cloned()
+                .unwrap_or(Backend::Native),
+            settle: matches
+                .get_one::<humantime::Duration>("settle")
+                .map(|d| (*d).into()),
+            git: git_root.as_deref(),
+            pause_while: match matches.values_of("pausewhile") {
+                Some(vr) => vr.collect(),
+                None => Vec::new(),
+            },
+            allow_missing: matches.is_present("allowmissing") || matches.is_present("fireoncreate"),
+            fire_on_create: matches.is_present("fireoncreate"),
+            initial_run: if matches.is_present("noinitialrun") {
+                None
+            } else {
+                Some(
+                    matches
+                        .get_one::<humantime::Duration>("initialdelay")
+                        .map(|d| (*d).into())
+                        .unwrap_or_else(|| Duration::from_secs(0)),
+                )
+            },
+            ignore_own_changes: if matches.is_present("ignoreown") {
+                Some(
+                    matches
+                        .get_one::<humantime::Duration>("owngrace")
+                        .map(|d| (*d).into())
+                        .unwrap_or_else(|| Duration::from_secs(2)),
+                )
+            } else {
+                None
+            },
+            routes,
+            files_from: matches.value_of("filesfrom"),
+            reload_files_from: matches.is_present("reloadfilesfrom"),
+            fire_on_rescan: matches.is_present("fireonrescan"),
+            poll_interval: matches
+                .get_one::<humantime::Duration>("pollinterval")
+                .map(|d| (*d).into())
+                .unwrap_or_else(|| Duration::from_secs(1)),
+            poll: duration,
+        };
+        Box::new(FileProcess::new(maybe_cmd, maybe_env, file, opts))
     } else if let Some(matches) = app.subcommand_matches("timer") {
         // TODO(jwall): This should use cancelable commands.
-        // Unwrap because this flag is required.
-        let duration = matches
-            .get_one::<humantime::Duration>("duration")
-            .expect("duration flag is required")
-            .clone();
+        // Unwrap because one of these flags is required.
+        let schedule = match matches.get_one::<cron::CronSchedule>("cron") {
+            Some(cron) => Schedule::Cron(cron.clone()),
+            None => {
+                let duration = *matches
+                    .get_one::<humantime::Duration>("duration")
+                    .expect("duration or cron flag is required");
+                let align = matches.is_present("align");
+                let mode = match matches.get_one::<IntervalMode>("schedule") {
+                    Some(mode) => *mode,
+                    None if align => IntervalMode::FixedRate,
+                    None => IntervalMode::FixedDelay,
+                };
+                Schedule::Every(Interval::new(*duration, mode, align))
+            }
+        };
         let max_repeat = matches.get_one::<u32>("repeat").cloned();
-        Box::new(TimerProcess::new(cmd, maybe_env, *duration, max_repeat))
+        Box::new(TimerProcess::new(cmd(), maybe_env, schedule, max_repeat))
+    } else if let Some(matches) = app.subcommand_matches("spool") {
+        // Unwrap because this flag is required.
+        let dir = matches.value_of("dir").expect("dir flag is required");
+        Box::new(SpoolProcess::new(
+            cmd(),
+            maybe_env,
+            dir,
+            matches.value_of("done"),
+            matches.value_of("failed"),
+            matches
+                .get_one::<humantime::Duration>("settle")
+                .map(|d| (*d).into()),
+            matches.is_present("ignorepartial"),
+        ))
+    } else if let Some(matches) = app.subcommand_matches("stale") {
+        // Unwrap because these flags are required.
+        let files = matches
+            .values_of("file")
+            .expect("file flag is required")
+            .collect();
+        let max_age = *matches
+            .get_one::<humantime::Duration>("maxage")
+            .expect("max-age flag is required");
+        Box::new(StaleProcess::new(cmd(), maybe_env, files, *max_age))
+    } else if app.subcommand_matches("presets").is_some() {
+        presets::print_list();
+        return;
     } else if let Some(matches) = app.subcommand_matches("success") {
         // unwrap because this is required.
         let ifcmd = matches.value_of("ifcmd").expect("ifcmd flag is required");
@@ -120,13 +399,13 @@ fn main() {
             .get_one::<humantime::Duration>("poll")
             .cloned()
             .unwrap_or(humantime::Duration::from_str("5s").unwrap());
-        Box::new(ExecProcess::new(ifcmd, cmd, negate, maybe_env, duration))
+        Box::new(ExecProcess::new(ifcmd, cmd(), negate, maybe_env, duration))
     } else {
         println!("You must specify a subcommand.");
         process::exit(1)
     };
     match proc.run() {
-        Ok(_) => return,
+        Ok(_) => (),
         Err(err) => {
             println!("{0}", err);
             process::exit(1)
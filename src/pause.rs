@@ -0,0 +1,68 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How often to check whether the paths holding changes back are gone.
+pub const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Holds changes back while any of its paths exist, like `.git/index.lock`
+/// during a commit or `.git/rebase-merge` during a rebase, and lets them
+/// through as a single batch once they are all gone.
+pub struct PauseGate {
+    paths: Vec<PathBuf>,
+    held: Option<Vec<PathBuf>>,
+}
+
+impl PauseGate {
+    pub fn new(paths: Vec<PathBuf>) -> PauseGate {
+        PauseGate { paths, held: None }
+    }
+
+    fn blocker(&self) -> Option<&PathBuf> {
+        self.paths.iter().find(|p| p.exists())
+    }
+
+    /// Returns true if changes are being held back.
+    pub fn is_holding(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// Returns the changes if they can be sent now or holds them back until
+    /// `release` lets them through.
+    pub fn pass(&mut self, paths: Vec<PathBuf>) -> Option<Vec<PathBuf>> {
+        if self.held.is_none() {
+            match self.blocker() {
+                Some(blocker) => println!("Holding changes while {:?} exists", blocker),
+                None => return Some(paths),
+            }
+        }
+        let held = self.held.get_or_insert_with(Vec::new);
+        for p in paths {
+            if !held.contains(&p) {
+                held.push(p);
+            }
+        }
+        None
+    }
+
+    /// Returns the held changes once none of the paths exist anymore.
+    pub fn release(&mut self) -> Option<Vec<PathBuf>> {
+        if self.held.is_none() || self.blocker().is_some() {
+            return None;
+        }
+        println!("Running the changes held while paused");
+        self.held.take()
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use notify::{self, watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use error::CommandError;
+use traits::WatchBackend;
+
+/// The parts of a file's metadata we compare between scans.
+#[derive(PartialEq, Clone, Copy)]
+struct Stat {
+    mtime: Option<SystemTime>,
+    size: u64,
+    ino: u64,
+    mode: u32,
+    is_dir: bool,
+}
+
+impl Stat {
+    #[cfg(unix)]
+    fn new(md: &Metadata) -> Stat {
+        use std::os::unix::fs::MetadataExt;
+        Stat {
+            mtime: md.modified().ok(),
+            size: md.len(),
+            ino: md.ino(),
+            mode: md.mode(),
+            is_dir: md.is_dir(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(md: &Metadata) -> Stat {
+        Stat {
+            mtime: md.modified().ok(),
+            size: md.len(),
+            ino: 0,
+            mode: md.permissions().readonly() as u32,
+            is_dir: md.is_dir(),
+        }
+    }
+}
+
+type Snapshot = HashMap<PathBuf, Stat>;
+
+fn scan_into(path: &Path, recursive: bool, snapshot: &mut Snapshot) {
+    // NOTE: symlink_metadata so a link back up the tree can't send us
+    // around in circles.
+    let md = match fs::symlink_metadata(path) {
+        Ok(md) => md,
+        Err(_) => return,
+    };
+    snapshot.insert(path.to_path_buf(), Stat::new(&md));
+    if !md.is_dir() {
+        return;
+    }
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            let child = entry.path();
+            if recursive {
+                scan_into(&child, true, snapshot);
+            } else if let Ok(md) = entry.metadata() {
+                snapshot.insert(child, Stat::new(&md));
+            }
+        }
+    }
+}
+
+fn scan(roots: &[(PathBuf, RecursiveMode)]) -> Snapshot {
+    let mut snapshot = HashMap::new();
+    for (root, mode) in roots {
+        scan_into(root, *mode == RecursiveMode::Recursive, &mut snapshot);
+    }
+    snapshot
+}
+
+/// Compares two snapshots and returns the events that explain the
+/// difference.
+fn diff(old: &Snapshot, new: &Snapshot) -> Vec<DebouncedEvent> {
+    let mut events = Vec::new();
+    let mut removed: Vec<(&PathBuf, &Stat)> =
+        old.iter().filter(|(p, _)| !new.contains_key(*p)).collect();
+    for (path, stat) in new.iter() {
+        match old.get(path) {
+            Some(prev) if prev == stat => (),
+            Some(prev) => {
+                if prev.ino != stat.ino || prev.size != stat.size || prev.mtime != stat.mtime {
+                    // A directory's mtime changes whenever an entry does and
+                    // the entry gets its own event.
+                    if !stat.is_dir || prev.ino != stat.ino {
+                        events.push(DebouncedEvent::Write(path.clone()));
+                    }
+                } else {
+                    events.push(DebouncedEvent::Chmod(path.clone()));
+                }
+            }
+            None => {
+                // The same inode disappearing from one path and showing up
+                // at another in a single scan is a rename.
+                let renamed = removed
+                    .iter()
+                    .position(|(_, s)| s.ino != 0 && s.ino == stat.ino);
+                match renamed {
+                    Some(idx) => {
+                        let (from, _) = removed.remove(idx);
+                        events.push(DebouncedEvent::Rename(from.clone(), path.clone()));
+                    }
+                    None => events.push(DebouncedEvent::Create(path.clone())),
+                }
+            }
+        }
+    }
+    for (path, _) in removed {
+        events.push(DebouncedEvent::Remove(path.clone()));
+    }
+    events
+}
+
+struct PollState {
+    roots: Vec<(PathBuf, RecursiveMode)>,
+    snapshot: Snapshot,
+}
+
+/// A watcher that stats the watched trees on an interval. It works on
+/// filesystems that never deliver native events like NFS, some container
+/// bind mounts and WSL shares.
+pub struct PollWatcher {
+    state: Arc<Mutex<PollState>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PollWatcher {
+    pub fn new(tx: Sender<DebouncedEvent>, interval: Duration) -> PollWatcher {
+        let state = Arc::new(Mutex::new(PollState {
+            roots: Vec::new(),
+            snapshot: HashMap::new(),
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        thread::spawn({
+            let state = state.clone();
+            let stop = stop.clone();
+            move || loop {
+                thread::sleep(interval);
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                let mut state = state.lock().expect("Poll state lock poisoned");
+                let snapshot = scan(&state.roots);
+                for evt in diff(&state.snapshot, &snapshot) {
+                    if tx.send(evt).is_err() {
+                        return;
+                    }
+                }
+                state.snapshot = snapshot;
+            }
+        });
+        PollWatcher { state, stop }
+    }
+}
+
+impl WatchBackend for PollWatcher {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), CommandError> {
+        let mut state = self.state.lock().expect("Poll state lock poisoned");
+        state.roots.push((path.to_path_buf(), mode));
+        // Take the first snapshot now so changes made right after we start
+        // watching are noticed.
+        scan_into(path, mode == RecursiveMode::Recursive, &mut state.snapshot);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), CommandError> {
+        let mut state = self.state.lock().expect("Poll state lock poisoned");
+        state.roots.retain(|(p, _)| p != path);
+        let roots = state.roots.clone();
+        // Keep entries another root still covers.
+        state.snapshot = scan(&roots);
+        Ok(())
+    }
+}
+
+impl Drop for PollWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The errno inotify returns when the watch limit has been reached.
+const ENOSPC: i32 = 28;
+
+const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+fn is_watch_limit(err: &notify::Error) -> bool {
+    match err {
+        notify::Error::Io(e) => e.raw_os_error() == Some(ENOSPC),
+        _ => false,
+    }
+}
+
+/// Watches paths with the native api but polls the ones it can't watch
+/// because the inotify watch limit was reached.
+pub struct FallbackWatcher {
+    native: RecommendedWatcher,
+    poll: Option<PollWatcher>,
+    polled: Vec<PathBuf>,
+    tx: Sender<DebouncedEvent>,
+    interval: Duration,
+}
+
+impl FallbackWatcher {
+    pub fn new(
+        tx: Sender<DebouncedEvent>,
+        delay: Duration,
+        interval: Duration,
+    ) -> Result<FallbackWatcher, CommandError> {
+        Ok(FallbackWatcher {
+            native: watcher(tx.clone(), delay)?,
+            poll: None,
+            polled: Vec::new(),
+            tx,
+            interval,
+        })
+    }
+}
+
+impl WatchBackend for FallbackWatcher {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), CommandError> {
+        match Watcher::watch(&mut self.native, path, mode) {
+            Ok(()) => Ok(()),
+            Err(ref err) if is_watch_limit(err) => {
+                let limit = fs::read_to_string(MAX_USER_WATCHES)
+                    .map(|l| l.trim().to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                println!(
+                    "Reached the inotify watch limit of {} while watching {:?}. Polling it instead.",
+                    limit, path
+                );
+                println!(
+                    "Raise the limit in {} or with sysctl fs.inotify.max_user_watches=<n>",
+                    MAX_USER_WATCHES
+                );
+                // NOTE: A recursive watch can fail part way down the
+                // tree so drop whatever it managed to add before polling.
+                let _ = Watcher::unwatch(&mut self.native, path);
+                let (tx, interval) = (&self.tx, self.interval);
+                self.poll
+                    .get_or_insert_with(|| PollWatcher::new(tx.clone(), interval))
+                    .watch(path, mode)?;
+                self.polled.push(path.to_path_buf());
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), CommandError> {
+        if let Some(idx) = self.polled.iter().position(|p| p == path) {
+            self.polled.remove(idx);
+            if let Some(ref mut poll) = self.poll {
+                return poll.unwatch(path);
+            }
+        }
+        Ok(Watcher::unwatch(&mut self.native, path)?)
+    }
+}
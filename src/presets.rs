@@ -0,0 +1,103 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use error::CommandError;
+
+/// A named set of filter and exclude rules for a common kind of project.
+/// The rules use the same glob format as `--filter` and `--exclude`.
+pub struct Preset {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub include: &'static [&'static str],
+    pub exclude: &'static [&'static str],
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "rust",
+        about: "Rust sources and manifests, skipping cargo's build output",
+        include: &["*.rs", "Cargo.toml", "Cargo.lock"],
+        exclude: &["target"],
+    },
+    Preset {
+        name: "node",
+        about: "JavaScript and TypeScript sources, skipping installed packages and bundles",
+        include: &["*.js", "*.mjs", "*.cjs", "*.jsx", "*.ts", "*.tsx", "*.json"],
+        exclude: &["node_modules", "dist", "coverage"],
+    },
+    Preset {
+        name: "python",
+        about: "Python sources and packaging files, skipping bytecode, caches and virtualenvs",
+        include: &["*.py", "pyproject.toml", "setup.cfg", "requirements*.txt"],
+        exclude: &[
+            "__pycache__",
+            "*.pyc",
+            ".venv",
+            "venv",
+            ".tox",
+            ".pytest_cache",
+            ".mypy_cache",
+            "*.egg-info",
+        ],
+    },
+    Preset {
+        name: "go",
+        about: "Go sources and module files",
+        include: &["*.go", "go.mod", "go.sum"],
+        exclude: &[],
+    },
+    Preset {
+        name: "editors",
+        about: "Skip the swap, backup and lock files editors write while saving",
+        include: &[],
+        exclude: &[
+            "*.swp",
+            "*.swo",
+            "*.swx",
+            "*~",
+            ".#*",
+            "#*#",
+            "4913",
+            "*___jb_tmp___",
+            "*___jb_old___",
+        ],
+    },
+];
+
+/// Looks a preset up by name.
+pub fn find(name: &str) -> Result<&'static Preset, CommandError> {
+    PRESETS.iter().find(|p| p.name == name).ok_or_else(|| {
+        CommandError::new(format!(
+            "Unknown preset {}: expected one of {}",
+            name,
+            PRESETS
+                .iter()
+                .map(|p| p.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    })
+}
+
+/// Prints every preset and its rules.
+pub fn print_list() {
+    for preset in PRESETS {
+        println!("{}: {}", preset.name, preset.about);
+        if !preset.include.is_empty() {
+            println!("    filter:  {}", preset.include.join(" "));
+        }
+        if !preset.exclude.is_empty() {
+            println!("    exclude: {}", preset.exclude.join(" "));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often to look at files that are still changing.
+const SETTLE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What we compare to decide a file has stopped changing. Missing files
+/// have no stat and are settled as soon as they stay missing.
+fn stat(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+    fs::metadata(path)
+        .ok()
+        .map(|md| (md.len(), md.modified().ok()))
+}
+
+struct Pending {
+    path: PathBuf,
+    stat: Option<(u64, Option<SystemTime>)>,
+    // When the stat last changed.
+    since: Instant,
+}
+
+/// Holds changed paths back until their size and mtime have stopped
+/// changing for the settle period so files still being written by a
+/// transfer don't fire.
+///
+/// Like the debouncer it doesn't read the clock itself. Callers pass the
+/// current instant in and use `deadline` to know when to call `poll`.
+pub struct Settler {
+    period: Duration,
+    pending: Vec<Pending>,
+}
+
+impl Settler {
+    pub fn new(period: Duration) -> Settler {
+        Settler {
+            period,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Starts or restarts the wait for the paths to settle.
+    pub fn push(&mut self, now: Instant, paths: Vec<PathBuf>) {
+        for path in paths {
+            let stat = stat(&path);
+            match self.pending.iter_mut().find(|p| p.path == path) {
+                Some(p) => {
+                    p.stat = stat;
+                    p.since = now;
+                }
+                None => self.pending.push(Pending {
+                    path,
+                    stat,
+                    since: now,
+                }),
+            }
+        }
+    }
+
+    /// Returns when `poll` should next be called or None if nothing is
+    /// waiting to settle.
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        let first = self.pending.iter().map(|p| p.since + self.period).min()?;
+        // We can't rely on an event for every write so we check the files
+        // while we wait.
+        Some(first.min(now + SETTLE_CHECK_INTERVAL))
+    }
+
+    /// Returns the paths that have settled.
+    pub fn poll(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut settled = Vec::new();
+        let period = self.period;
+        self.pending.retain_mut(|p| {
+            let current = stat(&p.path);
+            if current != p.stat {
+                p.stat = current;
+                p.since = now;
+                return true;
+            }
+            if now.saturating_duration_since(p.since) >= period {
+                settled.push(p.path.clone());
+                return false;
+            }
+            true
+        });
+        settled
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use notify::{watcher, RecursiveMode, Watcher};
+
+use error::CommandError;
+use events::{get_paths, is_notice};
+use exec::CancelableProcess;
+use file::CHANGED_PATHS_VAR;
+use filter::absolute;
+use settle::Settler;
+use traits::Process;
+
+/// Runs a command once for every file that lands in an inbox directory and
+/// moves the file to a done or failed directory depending on how the command
+/// exited.
+pub struct SpoolProcess<'a> {
+    cmd: &'a str,
+    env: Option<Vec<String>>,
+    inbox: PathBuf,
+    done: PathBuf,
+    failed: PathBuf,
+    settle: Duration,
+    ignore_partial: bool,
+}
+
+impl<'a> SpoolProcess<'a> {
+    /// Creates a spool worker for `inbox`. Finished files are moved to
+    /// `done` and `failed`, which default to directories inside the inbox.
+    pub fn new(
+        cmd: &'a str,
+        env: Option<Vec<String>>,
+        inbox: &str,
+        done: Option<&str>,
+        failed: Option<&str>,
+        settle: Option<Duration>,
+        ignore_partial: bool,
+    ) -> SpoolProcess<'a> {
+        let inbox = absolute(Path::new(inbox));
+        SpoolProcess {
+            cmd,
+            env,
+            done: done.map_or_else(|| inbox.join("done"), |d| absolute(Path::new(d))),
+            failed: failed.map_or_else(|| inbox.join("failed"), |d| absolute(Path::new(d))),
+            inbox,
+            settle: settle.unwrap_or_else(|| Duration::from_secs(0)),
+            ignore_partial,
+        }
+    }
+
+    /// Returns true if the path is a file waiting in the inbox.
+    fn is_spooled(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        if self.ignore_partial && (name.starts_with('.') || name.ends_with(".part")) {
+            return false;
+        }
+        absolute(path).parent() == Some(&self.inbox) && path.is_file()
+    }
+
+    /// Runs the command for one file and moves it out of the inbox.
+    fn process(&self, path: &Path) -> Result<(), CommandError> {
+        // It may have been handled or removed while it settled.
+        if !self.is_spooled(path) {
+            return Ok(());
+        }
+        println!("Processing {:?}", path);
+        let mut exec = CancelableProcess::new(self.cmd, self.env.clone());
+        let arg = path.to_string_lossy().into_owned();
+        exec.set_extra_env(vec![(CHANGED_PATHS_VAR.to_string(), arg.clone())]);
+        exec.set_extra_args(vec![arg]);
+        let success = match exec.spawn() {
+            Ok(()) => exec.wait_success()?,
+            Err(err) => {
+                println!("Failed to start command");
+                println!("{:?}", err);
+                false
+            }
+        };
+        let dir = if success { &self.done } else { &self.failed };
+        // Unwrap because spooled paths always have a file name.
+        let dest = unused_dest(dir, Path::new(path.file_name().unwrap()));
+        println!("Moving {:?} to {:?}", path, dest);
+        move_file(path, &dest)
+    }
+}
+
+/// Returns a path for `name` in `dir` that doesn't exist yet so an earlier
+/// file with the same name is never overwritten. Later ones get a counter
+/// before the extension, like `report.1.csv`.
+fn unused_dest(dir: &Path, name: &Path) -> PathBuf {
+    let dest = dir.join(name);
+    if !dest.exists() {
+        return dest;
+    }
+    // Unwrap because spooled paths always have a file name.
+    let stem = name.file_stem().unwrap().to_string_lossy();
+    let ext = name.extension().map(|e| e.to_string_lossy());
+    (1..)
+        .map(|n| match ext {
+            Some(ref ext) => dir.join(format!("{}.{}.{}", stem, n, ext)),
+            None => dir.join(format!("{}.{}", stem, n)),
+        })
+        .find(|p| !p.exists())
+        .expect("ran out of file names")
+}
+
+/// Moves a file, copying it when it can't be renamed because the
+/// destination is on another filesystem.
+fn move_file(from: &Path, to: &Path) -> Result<(), CommandError> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    if let Err(err) = fs::copy(from, to) {
+        // Don't leave a partial copy behind.
+        let _ = fs::remove_file(to);
+        return Err(err.into());
+    }
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+impl<'a> Process for SpoolProcess<'a> {
+    fn run(&mut self) -> Result<(), CommandError> {
+        fs::create_dir_all(&self.done)?;
+        fs::create_dir_all(&self.failed)?;
+        let (tx, rx) = channel();
+        let mut watcher = watcher(tx, Duration::from_secs(1))?;
+        watcher.watch(&self.inbox, RecursiveMode::NonRecursive)?;
+        println!("Watching {:?}", self.inbox);
+        let mut settler = Settler::new(self.settle);
+        // NOTE: Start watching before we list the inbox so a file
+        // that arrives in between isn't missed.
+        let mut existing: Vec<PathBuf> = fs::read_dir(&self.inbox)?
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| self.is_spooled(p))
+            .collect();
+        existing.sort();
+        settler.push(Instant::now(), existing);
+        loop {
+            let event = match settler.deadline(Instant::now()) {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => {
+                            // Files are handled one at a time so a file never
+                            // has more than one run in flight.
+                            for path in settler.poll(Instant::now()) {
+                                if let Err(err) = self.process(&path) {
+                                    println!("Failed to process {:?}", path);
+                                    println!("{:?}", err);
+                                }
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => panic!("Channel was closed!!!"),
+                    }
+                }
+                None => rx.recv().expect("Channel was closed!!!"),
+            };
+            if is_notice(&event) {
+                continue;
+            }
+            let paths = get_paths(&event)
+                .into_iter()
+                .filter(|p| self.is_spooled(p))
+                .cloned()
+                .collect();
+            settler.push(Instant::now(), paths);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+use notify::{watcher, RecursiveMode, Watcher};
+
+use error::CommandError;
+use events::{get_paths, is_notice};
+use exec::CancelableProcess;
+use filter::absolute;
+use traits::Process;
+
+/// The environment variable holding the newline separated paths that went
+/// stale.
+pub const STALE_PATHS_VAR: &str = "RUNWHEN_STALE_PATHS";
+
+/// A file we expect to change regularly.
+struct Heartbeat {
+    path: PathBuf,
+    last_change: Instant,
+    // False once we have fired for the file until it changes again.
+    armed: bool,
+}
+
+/// Returns when the file was last modified, or now if we can't tell.
+fn last_modified(path: &Path) -> Instant {
+    let now = Instant::now();
+    let age = fs::metadata(path)
+        .and_then(|md| md.modified())
+        .ok()
+        .and_then(|mtime| SystemTime::now().duration_since(mtime).ok());
+    match age {
+        Some(age) => now.checked_sub(age).unwrap_or(now),
+        None => now,
+    }
+}
+
+/// Runs a command when watched files haven't been modified for too long.
+/// It's a dead man's switch for jobs that are supposed to touch a file on
+/// every run.
+pub struct StaleProcess<'a> {
+    exec: CancelableProcess,
+    files: Vec<&'a str>,
+    max_age: Duration,
+}
+
+impl<'a> StaleProcess<'a> {
+    pub fn new(
+        cmd: &str,
+        env: Option<Vec<String>>,
+        files: Vec<&'a str>,
+        max_age: Duration,
+    ) -> StaleProcess<'a> {
+        StaleProcess {
+            exec: CancelableProcess::new(cmd, env),
+            files,
+            max_age,
+        }
+    }
+}
+
+impl<'a> Process for StaleProcess<'a> {
+    fn run(&mut self) -> Result<(), CommandError> {
+        let (tx, rx) = channel();
+        let mut watcher = watcher(tx, Duration::from_secs(1))?;
+        let mut beats = Vec::new();
+        for file in self.files.iter() {
+            let path = absolute(Path::new(file));
+            // NOTE: We watch the directory so a file that is replaced
+            // rather than written, or doesn't exist yet, is still seen.
+            let dir = match path.parent() {
+                Some(dir) if dir.is_dir() => dir,
+                _ => {
+                    return Err(CommandError::new(format!(
+                        "No such directory for {:?}",
+                        file
+                    )))
+                }
+            };
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            println!("Watching {:?} for staleness", path);
+            beats.push(Heartbeat {
+                last_change: last_modified(&path),
+                path,
+                armed: true,
+            });
+        }
+        loop {
+            let deadline = beats
+                .iter()
+                .filter(|b| b.armed)
+                .map(|b| b.last_change + self.max_age)
+                .min();
+            let event = match deadline {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(event) => Some(event),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => panic!("Channel was closed!!!"),
+                    }
+                }
+                None => Some(rx.recv().expect("Channel was closed!!!")),
+            };
+            match event {
+                Some(event) => {
+                    if is_notice(&event) {
+                        continue;
+                    }
+                    let paths: Vec<PathBuf> =
+                        get_paths(&event).into_iter().map(|p| absolute(p)).collect();
+                    for beat in beats.iter_mut() {
+                        if paths.contains(&beat.path) && beat.path.exists() {
+                            if !beat.armed {
+                                println!("{:?} changed again, re-arming", beat.path);
+                            }
+                            beat.last_change = last_modified(&beat.path);
+                            beat.armed = true;
+                        }
+                    }
+                }
+                None => {
+                    let now = Instant::now();
+                    let mut stale = Vec::new();
+                    for beat in beats.iter_mut() {
+                        if beat.armed && now >= beat.last_change + self.max_age {
+                            println!(
+                                "{:?} hasn't changed in {}",
+                                beat.path,
+                                humantime::format_duration(self.max_age)
+                            );
+                            beat.armed = false;
+                            stale.push(beat.path.to_string_lossy().into_owned());
+                        }
+                    }
+                    if !stale.is_empty() {
+                        self.exec
+                            .set_extra_env(vec![(STALE_PATHS_VAR.to_string(), stale.join("\n"))]);
+                        if let Err(err) = self.exec.reset() {
+                            println!("Failed to start command");
+                            println!("{:?}", err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -11,16 +11,187 @@
 //  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
+use std::str::FromStr;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use exec::CancelableProcess;
+use chrono::{DateTime, Local};
+
+use cron::CronSchedule;
 use error::CommandError;
+use exec::CancelableProcess;
 use traits::Process;
 
+// NOTE: Long sleeps are broken up so a suspended machine or a changed
+// system clock doesn't leave us waiting long past the next run.
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The source of wall clock time for a `TimerProcess`.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Sleeps until the clock reaches `target`.
+fn sleep_until(clock: &dyn Clock, target: SystemTime) {
+    loop {
+        let remaining = match target.duration_since(clock.now()) {
+            Ok(remaining) if !remaining.is_zero() => remaining,
+            _ => return,
+        };
+        clock.sleep(remaining.min(CLOCK_CHECK_INTERVAL));
+    }
+}
+
+/// How the time between runs of an interval timer is measured.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum IntervalMode {
+    /// Runs start a fixed period apart however long the command takes. Runs
+    /// the command was still busy for are skipped.
+    FixedRate,
+    /// Each run starts a fixed period after the previous one finished.
+    FixedDelay,
+}
+
+impl FromStr for IntervalMode {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<IntervalMode, CommandError> {
+        match s.trim().to_lowercase().as_str() {
+            "fixed-rate" => Ok(IntervalMode::FixedRate),
+            "fixed-delay" => Ok(IntervalMode::FixedDelay),
+            other => Err(CommandError::new(format!(
+                "Unknown schedule {}: expected one of fixed-rate or fixed-delay",
+                other
+            ))),
+        }
+    }
+}
+
+/// Decides when each run of an interval timer starts.
+///
+/// It never reads the clock itself. The caller passes in the current time
+/// so the schedule can be driven by any `Clock`.
+pub struct Interval {
+    period: Duration,
+    mode: IntervalMode,
+    align: bool,
+    last: Option<SystemTime>,
+}
+
+impl Interval {
+    /// With `align` set runs start on multiples of the period since the
+    /// Unix epoch, so a 5m period fires at :00, :05, :10 and so on.
+    pub fn new(period: Duration, mode: IntervalMode, align: bool) -> Interval {
+        Interval {
+            period,
+            mode,
+            align,
+            last: None,
+        }
+    }
+
+    /// Returns the first time at or after `t` on the grid of periods that
+    /// starts at `anchor`.
+    fn tick_at_or_after(&self, anchor: SystemTime, t: SystemTime) -> SystemTime {
+        let period = self.period.as_nanos();
+        let since = match t.duration_since(anchor) {
+            Ok(since) => since.as_nanos(),
+            Err(_) => return anchor,
+        };
+        if period == 0 || since % period == 0 {
+            return t;
+        }
+        t + Duration::from_nanos((period - since % period) as u64)
+    }
+
+    /// Returns when the next run should start. `now` is the time the
+    /// previous run finished, or the start time for the first run.
+    pub fn next_run(&mut self, now: SystemTime) -> SystemTime {
+        let next = match (self.last, self.mode) {
+            (None, _) if self.align => self.tick_at_or_after(UNIX_EPOCH, now),
+            (None, _) => now,
+            (Some(last), IntervalMode::FixedRate) => {
+                let due = last + self.period;
+                if due < now {
+                    println!("Command ran past its next start time. Skipping missed runs");
+                }
+                self.tick_at_or_after(last, due.max(now))
+            }
+            (Some(_), IntervalMode::FixedDelay) if self.align => {
+                self.tick_at_or_after(UNIX_EPOCH, now + self.period)
+            }
+            (Some(_), IntervalMode::FixedDelay) => now + self.period,
+        };
+        self.last = Some(next);
+        next
+    }
+}
+
+/// When a `TimerProcess` runs its command.
+pub enum Schedule {
+    /// Run every period.
+    Every(Interval),
+    /// Run at each time the cron expression matches in local time.
+    Cron(CronSchedule),
+}
+
+impl Schedule {
+    fn next_run(&mut self, now: SystemTime) -> Result<SystemTime, CommandError> {
+        match self {
+            Schedule::Every(interval) => Ok(interval.next_run(now)),
+            Schedule::Cron(cron) => match cron.next_after(&DateTime::<Local>::from(now)) {
+                Some(next) => {
+                    println!("Next run at {}", next.format("%Y-%m-%d %H:%M:%S %Z"));
+                    Ok(next.into())
+                }
+                None => Err(CommandError::new("Cron schedule never fires again")),
+            },
+        }
+    }
+}
+
+/// Runs `job` each time the schedule fires until it has run `max_repeat`
+/// times, reading and waiting on time through `clock`.
+fn run_schedule<F>(
+    schedule: &mut Schedule,
+    clock: &dyn Clock,
+    max_repeat: Option<u32>,
+    mut job: F,
+) -> Result<(), CommandError>
+where
+    F: FnMut(),
+{
+    let mut counter = 0;
+    loop {
+        if max_repeat.is_some_and(|max| counter >= max) {
+            return Ok(());
+        }
+        let next = schedule.next_run(clock.now())?;
+        sleep_until(clock, next);
+        job();
+        if max_repeat.is_some() {
+            counter += 1
+        }
+    }
+}
+
 pub struct TimerProcess {
     cmd: CancelableProcess,
-    poll_duration: Duration,
+    schedule: Schedule,
     max_repeat: Option<u32>,
 }
 
@@ -28,13 +199,13 @@ impl TimerProcess {
     pub fn new(
         cmd: &str,
         env: Option<Vec<String>>,
-        poll_duration: Duration,
+        schedule: Schedule,
         max_repeat: Option<u32>,
     ) -> TimerProcess {
-    let cmd = CancelableProcess::new(cmd, env);
+        let cmd = CancelableProcess::new(cmd, env);
         TimerProcess {
             cmd,
-            poll_duration,
+            schedule,
             max_repeat,
         }
     }
@@ -42,18 +213,127 @@ impl TimerProcess {
 
 impl Process for TimerProcess {
     fn run(&mut self) -> Result<(), CommandError> {
-        let mut counter = 0;
-        loop {
-            if self.max_repeat.is_some() && counter >= self.max_repeat.unwrap() {
-                return Ok(());
-            }
-            if let Err(err) = self.cmd.block() {
+        let cmd = &mut self.cmd;
+        run_schedule(&mut self.schedule, &SystemClock, self.max_repeat, || {
+            if let Err(err) = cmd.block() {
                 println!("{:?}", err)
             }
-            thread::sleep(self.poll_duration);
-            if self.max_repeat.is_some() {
-                counter += 1
-            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// A clock that only moves when something sleeps on it or a fake job
+    /// advances it.
+    struct FakeClock {
+        now: Rc<Cell<SystemTime>>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            self.now.get()
         }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration)
+        }
+    }
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    /// Runs the schedule starting at `start` with jobs that take the given
+    /// durations and returns the time each run started.
+    fn run_starts(start: SystemTime, mut schedule: Schedule, jobs: &[u64]) -> Vec<SystemTime> {
+        let now = Rc::new(Cell::new(start));
+        let clock = FakeClock { now: now.clone() };
+        let starts = RefCell::new(Vec::new());
+        let mut jobs = jobs.iter();
+        run_schedule(&mut schedule, &clock, Some(jobs.len() as u32), || {
+            starts.borrow_mut().push(now.get());
+            now.set(now.get() + secs(*jobs.next().unwrap()));
+        })
+        .unwrap();
+        starts.into_inner()
+    }
+
+    fn interval(period: u64, mode: IntervalMode, align: bool) -> Schedule {
+        Schedule::Every(Interval::new(secs(period), mode, align))
+    }
+
+    #[test]
+    fn align_fires_on_multiples_of_the_period() {
+        // 11:58:17 UTC on some day.
+        let start = UNIX_EPOCH + secs(1_700_000_000 / 86400 * 86400 + 11 * 3600 + 58 * 60 + 17);
+        let midday = start + secs(2 * 60 - 17);
+        let starts = run_starts(
+            start,
+            interval(300, IntervalMode::FixedRate, true),
+            &[20, 20, 20],
+        );
+        assert_eq!(starts, vec![midday, midday + secs(300), midday + secs(600)]);
+    }
+
+    #[test]
+    fn fixed_rate_ignores_how_long_the_command_takes() {
+        let start = UNIX_EPOCH + secs(1_000_000);
+        let starts = run_starts(
+            start,
+            interval(60, IntervalMode::FixedRate, false),
+            &[20, 20, 20],
+        );
+        assert_eq!(starts, vec![start, start + secs(60), start + secs(120)]);
+    }
+
+    #[test]
+    fn fixed_rate_skips_ticks_the_command_ran_past() {
+        let start = UNIX_EPOCH + secs(1_000_000);
+        let starts = run_starts(
+            start,
+            interval(60, IntervalMode::FixedRate, false),
+            &[20, 150, 20, 20],
+        );
+        // The second run ends at 210s so the ticks at 120s and 180s are
+        // skipped.
+        assert_eq!(
+            starts,
+            vec![
+                start,
+                start + secs(60),
+                start + secs(240),
+                start + secs(300)
+            ]
+        );
+    }
+
+    #[test]
+    fn fixed_delay_waits_the_period_after_each_run_finishes() {
+        let start = UNIX_EPOCH + secs(1_000_000);
+        let starts = run_starts(
+            start,
+            interval(60, IntervalMode::FixedDelay, false),
+            &[20, 5, 20],
+        );
+        assert_eq!(starts, vec![start, start + secs(80), start + secs(145)]);
+    }
+
+    #[test]
+    fn aligned_fixed_delay_waits_for_the_next_boundary_after_the_delay() {
+        let start = UNIX_EPOCH + secs(1_000_000 * 60 + 10);
+        let minute = start + secs(50);
+        let starts = run_starts(
+            start,
+            interval(60, IntervalMode::FixedDelay, true),
+            &[20, 20],
+        );
+        // The first run ends at :20 so the delay ends at :20 a minute later
+        // and the next boundary after that is two minutes on.
+        assert_eq!(starts, vec![minute, minute + secs(120)]);
     }
 }
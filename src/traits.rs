@@ -11,8 +11,29 @@
 //  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
+use std::path::Path;
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
 use error::CommandError;
 
 pub trait Process {
     fn run(&mut self) -> Result<(), CommandError>;
 }
+
+/// Something that can watch paths and report changes to them as notify
+/// events.
+pub trait WatchBackend {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), CommandError>;
+    fn unwatch(&mut self, path: &Path) -> Result<(), CommandError>;
+}
+
+impl WatchBackend for RecommendedWatcher {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), CommandError> {
+        Ok(Watcher::watch(self, path, mode)?)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), CommandError> {
+        Ok(Watcher::unwatch(self, path)?)
+    }
+}
@@ -0,0 +1,544 @@
+// Copyright 2017 Jeremy Wall <jeremy@marzhillstudios.com>
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use notify::{DebouncedEvent, RecursiveMode};
+
+use error::CommandError;
+use events::{get_paths, is_notice};
+use filter::absolute;
+use traits::WatchBackend;
+
+/// How deep below a watched directory changes are reported.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum WatchDepth {
+    Recursive,
+    /// Only the directory's direct entries.
+    NonRecursive,
+    /// Entries at most this many levels below the directory.
+    Limited(usize),
+}
+
+impl WatchDepth {
+    /// Returns true if entries this many levels below the directory are
+    /// watched.
+    pub fn allows(&self, levels: usize) -> bool {
+        match self {
+            WatchDepth::Recursive => true,
+            WatchDepth::NonRecursive => levels <= 1,
+            WatchDepth::Limited(max) => levels <= *max,
+        }
+    }
+}
+
+impl FromStr for WatchDepth {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<WatchDepth, CommandError> {
+        match s {
+            "recursive" => Ok(WatchDepth::Recursive),
+            "non-recursive" | "nonrecursive" => Ok(WatchDepth::NonRecursive),
+            _ => match s.strip_prefix("depth=").map(|n| n.parse::<usize>()) {
+                Some(Ok(1)) => Ok(WatchDepth::NonRecursive),
+                Some(Ok(n)) if n > 1 => Ok(WatchDepth::Limited(n)),
+                _ => Err(CommandError::new(format!(
+                    "Invalid watch depth {}: expected recursive, non-recursive or depth=N with N at least 1",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// Splits a watch target like `src:recursive` or `logs:depth=2` into the
+/// path and its depth. Targets without a suffix get the default depth.
+pub fn parse_target(spec: &str, default: WatchDepth) -> Result<(&str, WatchDepth), CommandError> {
+    if let Some(idx) = spec.rfind(':') {
+        let (path, suffix) = (&spec[..idx], &spec[idx + 1..]);
+        let known = suffix.contains("recursive") || suffix.starts_with("depth=");
+        // NOTE: Paths may contain colons so anything that doesn't look
+        // like one of our suffixes is part of the path.
+        if known && !path.is_empty() {
+            return Ok((path, suffix.parse()?));
+        }
+    }
+    Ok((spec, default))
+}
+
+/// If the path is a glob pattern, returns the directory above its first
+/// wildcard along with how deep below that directory the pattern reaches.
+pub fn glob_base(path: &str) -> Option<(PathBuf, WatchDepth)> {
+    let is_glob = |s: &str| s.contains(['*', '?', '[']);
+    if !is_glob(path) {
+        return None;
+    }
+    let mut base = PathBuf::new();
+    let mut rest = Vec::new();
+    for c in Path::new(path).components() {
+        let s = c.as_os_str().to_string_lossy();
+        if rest.is_empty() && !is_glob(&s) {
+            base.push(c);
+        } else {
+            rest.push(s.into_owned());
+        }
+    }
+    if base.as_os_str().is_empty() {
+        base.push(".");
+    }
+    let depth = if rest.iter().any(|s| s == "**") {
+        WatchDepth::Recursive
+    } else if rest.len() <= 1 {
+        WatchDepth::NonRecursive
+    } else {
+        WatchDepth::Limited(rest.len())
+    };
+    Some((base, depth))
+}
+
+#[derive(PartialEq)]
+enum TargetState {
+    Watching,
+    // The target doesn't exist so we watch this ancestor for it to appear.
+    Waiting(PathBuf),
+}
+
+/// A path the user asked us to watch.
+struct Target {
+    given: PathBuf,
+    abs: PathBuf,
+    depth: WatchDepth,
+    state: TargetState,
+    // Identifies the file we attached the watch to so we can tell when an
+    // editor has replaced it with a new one.
+    file_id: Option<u64>,
+    // Set when a watched file was replaced and the new one hasn't appeared
+    // yet.
+    replaced: bool,
+    // Every path we asked the backend to watch for this target.
+    watched: Vec<PathBuf>,
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    match fs::metadata(path) {
+        Ok(md) if !md.is_dir() => Some(md.ino()),
+        _ => None,
+    }
+}
+
+// NOTE: Without inodes we can only tell a file was replaced from the
+// remove and rename events.
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Logs a failure to watch one path. One bad path shouldn't stop us
+/// watching the rest.
+fn report_watch_error(path: &Path, err: CommandError) {
+    println!("Failed to watch {:?}", path);
+    println!("{:?}", err);
+}
+
+/// Adds a watched path unless it is already recorded. A target can be
+/// watched again many times as atomic saves replace it.
+fn record(watched: &mut Vec<PathBuf>, path: &Path) {
+    if !watched.iter().any(|p| p == path) {
+        watched.push(path.to_path_buf());
+    }
+}
+
+/// Tracks the watch targets and keeps the backend watching them as they are
+/// created, deleted and recreated.
+pub struct WatchSet {
+    backend: Box<dyn WatchBackend>,
+    targets: Vec<Target>,
+    // Directories watched non-recursively on behalf of missing targets.
+    ancestors: Vec<PathBuf>,
+    allow_missing: bool,
+    fire_on_create: bool,
+}
+
+impl WatchSet {
+    /// Creates a set of watches. If `allow_missing` is set, targets that
+    /// don't exist yet or are deleted later are waited for instead of being
+    /// an error. If `fire_on_create` is set too, their creation fires the
+    /// command.
+    pub fn new(
+        backend: Box<dyn WatchBackend>,
+        allow_missing: bool,
+        fire_on_create: bool,
+    ) -> WatchSet {
+        WatchSet {
+            backend,
+            targets: Vec::new(),
+            ancestors: Vec::new(),
+            allow_missing,
+            fire_on_create,
+        }
+    }
+
+    /// Makes the watched targets match the list, watching new ones and
+    /// unwatching the ones that are no longer in it. If a new target is
+    /// missing and missing paths aren't allowed nothing is changed. A new
+    /// target that can't be watched is reported and skipped.
+    pub fn sync(&mut self, targets: &[(PathBuf, WatchDepth)]) -> Result<(), CommandError> {
+        let is_known = |set: &[Target], path: &Path, depth: WatchDepth| {
+            set.iter().any(|t| t.given == *path && t.depth == depth)
+        };
+        if !self.allow_missing {
+            for (path, depth) in targets.iter() {
+                // NOTE: this is necessary because notify::fsEventWatcher
+                // panics if the path doesn't exist. :-(
+                if !path.exists() && !is_known(&self.targets, path, *depth) {
+                    return Err(CommandError::new(format!(
+                        "No such path! {0}",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        let wanted = |t: &Target| targets.iter().any(|(p, d)| *p == t.given && *d == t.depth);
+        let (keep, dropped): (Vec<Target>, Vec<Target>) = std::mem::take(&mut self.targets)
+            .into_iter()
+            .partition(wanted);
+        self.targets = keep;
+        for target in dropped {
+            println!("No longer watching {:?}", target.given);
+            for path in target.watched.iter() {
+                let shared = self.targets.iter().any(|t| t.watched.contains(path));
+                if !shared {
+                    // The path may already be gone along with its watch.
+                    let _ = self.backend.unwatch(path);
+                }
+            }
+        }
+        for (path, depth) in targets.iter() {
+            if is_known(&self.targets, path, *depth) {
+                continue;
+            }
+            if let Err(err) = self.add(path, *depth) {
+                println!("Failed to watch {:?}", path);
+                println!("{:?}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts watching a path.
+    fn add(&mut self, path: &Path, depth: WatchDepth) -> Result<(), CommandError> {
+        let given = path.to_path_buf();
+        let abs = absolute(&given);
+        let mut target = Target {
+            given,
+            abs,
+            depth,
+            state: TargetState::Waiting(PathBuf::new()),
+            file_id: None,
+            replaced: false,
+            watched: Vec::new(),
+        };
+        self.establish(&mut target)?;
+        self.targets.push(target);
+        Ok(())
+    }
+
+    /// Watches the target if it exists or its nearest existing ancestor if
+    /// it doesn't. Returns true if the target is now being watched.
+    fn establish(&mut self, target: &mut Target) -> Result<bool, CommandError> {
+        if target.given.exists() {
+            match target.depth {
+                WatchDepth::Recursive => {
+                    self.backend
+                        .watch(&target.given, RecursiveMode::Recursive)?;
+                    record(&mut target.watched, &target.given);
+                }
+                WatchDepth::NonRecursive => {
+                    self.backend
+                        .watch(&target.given, RecursiveMode::NonRecursive)?;
+                    record(&mut target.watched, &target.given);
+                }
+                WatchDepth::Limited(max) => {
+                    self.watch_limited(&target.given, max, &mut target.watched)?
+                }
+            }
+            println!("Watching {:?}", target.given);
+            target.state = TargetState::Watching;
+            target.file_id = file_id(&target.given);
+            return Ok(true);
+        }
+        let ancestor = match target.abs.ancestors().skip(1).find(|p| p.is_dir()) {
+            Some(a) => a.to_path_buf(),
+            None => {
+                return Err(CommandError::new(format!(
+                    "No such path! {:?}",
+                    target.given
+                )))
+            }
+        };
+        if target.state != TargetState::Waiting(ancestor.clone()) {
+            println!(
+                "Waiting for {:?} to be created in {:?}",
+                target.given, ancestor
+            );
+        }
+        if !self.ancestors.contains(&ancestor) {
+            self.backend.watch(&ancestor, RecursiveMode::NonRecursive)?;
+            self.ancestors.push(ancestor.clone());
+        }
+        target.state = TargetState::Waiting(ancestor);
+        Ok(false)
+    }
+
+    /// Watches a directory and the directories below it non-recursively
+    /// down to `levels` below it, recording each one in `watched`.
+    fn watch_limited(
+        &mut self,
+        dir: &Path,
+        levels: usize,
+        watched: &mut Vec<PathBuf>,
+    ) -> Result<(), CommandError> {
+        self.backend.watch(dir, RecursiveMode::NonRecursive)?;
+        record(watched, dir);
+        if levels <= 1 || !dir.is_dir() {
+            return Ok(());
+        }
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                // file_type doesn't follow symlinks so we can't loop.
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    self.watch_limited(&entry.path(), levels - 1, watched)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the path is inside a target we are watching, and
+    /// within its depth, rather than an ancestor we are only watching for a
+    /// missing target.
+    pub fn is_watched(&self, path: &Path) -> bool {
+        let abs = absolute(path);
+        self.targets.iter().any(|t| {
+            t.state == TargetState::Watching
+                && match abs.strip_prefix(&t.abs) {
+                    Ok(rel) => t.depth.allows(rel.components().count()),
+                    Err(_) => false,
+                }
+        })
+    }
+
+    /// Extends depth limited watches to a directory created inside them.
+    fn watch_new_dir(&mut self, target: &mut Target, dir: &Path) -> Result<(), CommandError> {
+        let max = match target.depth {
+            WatchDepth::Limited(max) => max,
+            _ => return Ok(()),
+        };
+        if let Ok(rel) = dir.strip_prefix(&target.abs) {
+            let levels = rel.components().count();
+            if levels > 0 && levels < max && dir.is_dir() {
+                self.watch_limited(dir, max - levels, &mut target.watched)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the event means a watched file was replaced by a new
+    /// file at the same path, as editors do when they save by writing a
+    /// temporary file and renaming it over the original.
+    fn was_replaced(target: &Target, event: &DebouncedEvent) -> bool {
+        if target.given.is_dir() {
+            return false;
+        }
+        let moved = match event {
+            DebouncedEvent::Remove(p) | DebouncedEvent::Rename(p, _) => absolute(p) == target.abs,
+            _ => false,
+        };
+        moved || target.file_id != file_id(&target.given)
+    }
+
+    /// Updates the watches after an event. Returns the targets that were
+    /// created by it if they should fire.
+    pub fn handle_event(&mut self, event: &DebouncedEvent) -> Vec<PathBuf> {
+        // The debounced event that follows a notice is the one to act on.
+        if is_notice(event) {
+            return Vec::new();
+        }
+        let paths: Vec<PathBuf> = get_paths(event).into_iter().map(|p| absolute(p)).collect();
+        let rescan = matches!(event, DebouncedEvent::Rescan);
+        let mut appeared = Vec::new();
+        let mut targets = std::mem::take(&mut self.targets);
+        for target in targets.iter_mut() {
+            if target.state == TargetState::Watching {
+                match event {
+                    DebouncedEvent::Create(p) | DebouncedEvent::Rename(_, p) => {
+                        // The directory may be unreadable or already gone.
+                        if let Err(err) = self.watch_new_dir(target, &absolute(p)) {
+                            report_watch_error(p, err);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            // Only events on the target itself or the directories above it
+            // can change whether it exists.
+            let relevant = rescan || paths.iter().any(|p| target.abs.starts_with(p));
+            if !relevant {
+                continue;
+            }
+            match target.state {
+                TargetState::Watching => {
+                    if !Self::was_replaced(target, event) {
+                        continue;
+                    }
+                    if target.given.exists() {
+                        println!("{:?} was replaced", target.given);
+                    } else if self.allow_missing || target.file_id.is_some() {
+                        // A replaced file usually reappears in a moment so we
+                        // wait for it even if missing paths aren't allowed.
+                        println!("{:?} was removed", target.given);
+                        target.replaced = target.file_id.is_some();
+                    } else {
+                        continue;
+                    }
+                    if let Err(err) = self.establish(target) {
+                        report_watch_error(&target.given, err);
+                    }
+                }
+                TargetState::Waiting(_) => match self.establish(target) {
+                    Ok(watching) => {
+                        if watching && (self.fire_on_create || target.replaced) {
+                            appeared.push(target.abs.clone());
+                        }
+                        target.replaced = false;
+                    }
+                    Err(err) => report_watch_error(&target.given, err),
+                },
+            }
+        }
+        self.targets = targets;
+        appeared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::process;
+    use std::rc::Rc;
+
+    /// Records the paths being watched instead of watching them.
+    struct FakeBackend {
+        watched: Rc<RefCell<Vec<PathBuf>>>,
+    }
+
+    impl WatchBackend for FakeBackend {
+        fn watch(&mut self, path: &Path, _mode: RecursiveMode) -> Result<(), CommandError> {
+            if path.ends_with("unreadable") {
+                return Err(CommandError::new("Permission denied"));
+            }
+            self.watched.borrow_mut().push(path.to_path_buf());
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> Result<(), CommandError> {
+            self.watched.borrow_mut().retain(|p| p != path);
+            Ok(())
+        }
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("runwhen-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sync_with_a_missing_path_keeps_the_current_targets() {
+        let dir = scratch_dir("sync-missing");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+        let watched = Rc::new(RefCell::new(Vec::new()));
+        let backend = FakeBackend {
+            watched: watched.clone(),
+        };
+        let mut set = WatchSet::new(Box::new(backend), false, false);
+        set.sync(&[
+            (a.clone(), WatchDepth::Recursive),
+            (b.clone(), WatchDepth::Recursive),
+        ])
+        .unwrap();
+
+        let reloaded = [
+            (a.clone(), WatchDepth::Recursive),
+            (dir.join("missing.txt"), WatchDepth::Recursive),
+        ];
+        assert!(set.sync(&reloaded).is_err());
+        assert_eq!(*watched.borrow(), vec![a.clone(), b.clone()]);
+        assert!(set.is_watched(&a));
+        assert!(set.is_watched(&b));
+
+        // Once the list is fixed the dropped target is unwatched.
+        set.sync(&[(a.clone(), WatchDepth::Recursive)]).unwrap();
+        assert_eq!(*watched.borrow(), vec![a.clone()]);
+        assert!(!set.is_watched(&b));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sync_waits_for_missing_paths_when_allowed() {
+        let dir = scratch_dir("sync-allowed");
+        let missing = dir.join("later.txt");
+        let watched = Rc::new(RefCell::new(Vec::new()));
+        let backend = FakeBackend {
+            watched: watched.clone(),
+        };
+        let mut set = WatchSet::new(Box::new(backend), true, false);
+        set.sync(&[(missing.clone(), WatchDepth::Recursive)])
+            .unwrap();
+        // Only the directory it will appear in is watched for now.
+        assert_eq!(*watched.borrow(), vec![absolute(&dir)]);
+        assert!(!set.is_watched(&missing));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_new_directory_that_cant_be_watched_is_skipped() {
+        let dir = absolute(&scratch_dir("new-dir"));
+        let watched = Rc::new(RefCell::new(Vec::new()));
+        let backend = FakeBackend {
+            watched: watched.clone(),
+        };
+        let mut set = WatchSet::new(Box::new(backend), false, false);
+        set.sync(&[(dir.clone(), WatchDepth::Limited(3))]).unwrap();
+
+        let bad = dir.join("unreadable");
+        let good = dir.join("fine");
+        fs::create_dir_all(&bad).unwrap();
+        fs::create_dir_all(&good).unwrap();
+        assert!(set.handle_event(&DebouncedEvent::Create(bad)).is_empty());
+        assert!(set
+            .handle_event(&DebouncedEvent::Create(good.clone()))
+            .is_empty());
+        assert_eq!(*watched.borrow(), vec![dir.clone(), good]);
+        assert!(set.is_watched(&dir.join("a.txt")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}